@@ -1,111 +1,394 @@
-// use rcgen::{Issuer, SanType};
-// use std::time::Duration;
-// use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, KeyUsagePurpose};
-// use rcgen::string::Ia5String;
-// use time::OffsetDateTime;
-// 
-// fn generate_root_ca() -> Result<(Certificate, KeyPair), rcgen::Error> {
-//     // Create custom parameters for the root CA
-//     let mut params = CertificateParams::default();
-// 
-//     // Set the CA certificate to be valid for 10 years
-//     let now = OffsetDateTime::now_utc();
-//     params.not_before = now;
-//     params.not_after = now + Duration::from_secs(10 * 365 * 24 * 60 * 60);
-// 
-//     // Set this as a CA certificate
-//     params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-// 
-//     // Set key usages appropriate for a CA
-//     params.key_usages = vec![
-//         KeyUsagePurpose::DigitalSignature,
-//         KeyUsagePurpose::KeyCertSign,
-//         KeyUsagePurpose::CrlSign,
-//     ];
-// 
-//     // Set CA name information
-//     let mut distinguished_name = DistinguishedName::new();
-//     distinguished_name.push(DnType::CommonName, "DagProxy Root CA");
-//     distinguished_name.push(DnType::OrganizationName, "DagProxy");
-//     distinguished_name.push(DnType::CountryName, "US");
-//     params.distinguished_name = distinguished_name;
-// 
-//     // Generate a key pair for the CA
-//     let key_pair = KeyPair::generate()?;
-// 
-//     // Create and self-sign the CA certificate
-//     let cert = params.self_signed(&key_pair).unwrap();
-// 
-//     Ok((cert, key_pair))
-// }
-// 
-// fn issue_certificate(
-//     ca_cert: &Certificate,
-//     ca_key_pair: &KeyPair,
-//     domain_name: &str
-// ) -> Result<Certificate, rcgen::Error> {
-//     // Create parameters for the leaf certificate
-//     let mut params = CertificateParams::default();
-// 
-//     // Set the certificate to be valid for 1 year
-//     let now = OffsetDateTime::now_utc();
-//     params.not_before = now;
-//     params.not_after = now + Duration::from_secs(365 * 24 * 60 * 60);
-// 
-//     // Add the domain name as both Common Name and Subject Alternative Name
-//     let mut distinguished_name = DistinguishedName::new();
-//     distinguished_name.push(DnType::CommonName, domain_name);
-//     params.distinguished_name = distinguished_name;
-// 
-//     // Add Subject Alternative Name
-//     params.subject_alt_names.push(SanType::DnsName(Ia5String::try_from(domain_name).unwrap()));
-// 
-//     // This is NOT a CA certificate
-//     params.is_ca = IsCa::NoCa;
-// 
-//     // Set key usages for a server certificate
-//     params.key_usages = vec![
-//         KeyUsagePurpose::DigitalSignature,
-//         KeyUsagePurpose::KeyEncipherment,
-//     ];
-// 
-// 
-//     // Create an issuer from the CA certificate and private key
-//     let issuer = Issuer::from_ca_cert_der(ca_cert.der(), &ca_key_pair)?;
-// 
-//     // Create and sign the certificate with the CA
-//     let cert = params.signed_by(&ca_key_pair, &issuer)?;
-//     Ok(cert)
-// }
-// 
-// fn get_issuer_from_pem<'a>(pem_str: &str, private_key_pem: &str) -> Result<Issuer<'a, KeyPair>, rcgen::Error> {
-//     let signing_key = KeyPair::from_pem(&private_key_pem)?;
-//     let issuer = Issuer::from_ca_cert_pem(&pem_str, signing_key)?;
-//     Ok(issuer)
-// }
-// 
-// 
-// 
-// #[cfg(test)]
-// mod tests{
-//     use rcgen::{generate_simple_self_signed, CertificateParams, CertifiedKey};
-//     use crate::cert::{generate_root_ca, issue_certificate};
-// 
-//     #[test]
-//     fn test_cert(){
-//         let (cert, signing_key) = generate_root_ca().unwrap();
-// 
-// 
-//         println!("{}", cert.pem());
-//         println!("{}", signing_key.serialize_pem());
-//     }
-// 
-//     #[test]
-//     fn test_cert_2(){
-//         let (cert, signing_key) = generate_root_ca().unwrap();
-//         let test = issue_certificate(&cert, &signing_key, "shlasouf.com").unwrap();
-//         println!("{}", test.pem());
-//     }
-// 
-// 
-// }
\ No newline at end of file
+use crate::config::CaConfig;
+use crate::hex::hex;
+use anyhow::anyhow;
+use rcgen::string::Ia5String;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair, KeyUsagePurpose, SanType};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use x509_parser::pem::Pem;
+
+const CA_CERT_FILE: &str = "dagproxy-ca.pem";
+const CA_KEY_FILE: &str = "dagproxy-ca.key";
+const LEAF_DIR: &str = "leaves";
+/// Leaf certificates are issued again once they get this close to expiring.
+const LEAF_RENEW_BEFORE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The local root CA, generated on first use and kept in [`CaConfig::dir`].
+pub(crate) struct RootCa {
+    dir: PathBuf,
+    cert_pem: String,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl RootCa {
+    /// Loads the CA from `config.dir`, generating and saving it the first time.
+    pub(crate) fn load_or_generate(config: &CaConfig) -> Result<Self, anyhow::Error> {
+        let dir = ca_dir(config)?;
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+
+        match (cert_path.exists(), key_path.exists()) {
+            (true, true) => {
+                check_private(&key_path)?;
+                let cert_pem = fs::read_to_string(&cert_path)?;
+                let issuer = get_issuer_from_pem(&cert_pem, &fs::read_to_string(&key_path)?)?;
+                return Ok(Self { dir, cert_pem, issuer });
+            }
+            // A new CA would silently replace the one installed in trust stores.
+            (true, false) | (false, true) => {
+                return Err(anyhow!(
+                    "Only one of {} and {} is in {}, restore the other one or remove both to generate a new CA",
+                    CA_CERT_FILE,
+                    CA_KEY_FILE,
+                    dir.display()
+                ));
+            }
+            (false, false) => {}
+        }
+
+        let (cert, key_pair) = generate_root_ca()?;
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+            }
+        }
+        write_private(&key_path, key_pair.serialize_pem().as_bytes())?;
+        fs::write(&cert_path, cert.pem())?;
+        // Leaves signed by a previous CA would not be trusted anymore.
+        let _ = fs::remove_dir_all(dir.join(LEAF_DIR));
+        eprintln!("🔐 Generated a root CA in {}", dir.display());
+
+        Ok(Self {
+            dir,
+            cert_pem: cert.pem(),
+            issuer: Issuer::from_ca_cert_der(cert.der(), key_pair)?,
+        })
+    }
+
+    /// The CA certificate, to be installed in the OS or browser trust store.
+    pub(crate) fn pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// A certificate for `names` signed by the CA, along with its own key. The first name is also the common name.
+    pub(crate) fn issue_certificate(&self, names: &[&str]) -> Result<(Certificate, KeyPair), rcgen::Error> {
+        let mut params = CertificateParams::default();
+
+        // Browsers refuse leaf certificates valid for more than 398 days.
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::from_secs(24 * 60 * 60);
+        params.not_after = now + Duration::from_secs(397 * 24 * 60 * 60);
+
+        let mut distinguished_name = DistinguishedName::new();
+        if let Some(common_name) = names.first() {
+            distinguished_name.push(DnType::CommonName, *common_name);
+        }
+        params.distinguished_name = distinguished_name;
+
+        for name in names {
+            params.subject_alt_names.push(match name.parse::<IpAddr>() {
+                Ok(ip) => SanType::IpAddress(ip),
+                Err(_) => SanType::DnsName(Ia5String::try_from(*name)?),
+            });
+        }
+
+        params.is_ca = IsCa::NoCa;
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+        let key_pair = KeyPair::generate()?;
+        let cert = params.signed_by(&key_pair, &self.issuer)?;
+        Ok((cert, key_pair))
+    }
+}
+
+/// A leaf certificate and its PKCS#8 key, both DER encoded.
+pub(crate) struct LeafCertificate {
+    pub cert_der: Vec<u8>,
+    pub key_der: Vec<u8>,
+    not_after: OffsetDateTime,
+}
+
+impl LeafCertificate {
+    fn new(cert_der: Vec<u8>, key_der: Vec<u8>) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert_der).ok()?;
+        let not_after = cert.validity().not_after.to_datetime();
+        Some(Self { cert_der, key_der, not_after })
+    }
+
+    /// Reads a certificate saved by [`LeafCache`], the certificate and its key in one PEM file.
+    fn from_pem(data: &[u8]) -> Option<Self> {
+        let (mut cert_der, mut key_der) = (None, None);
+        for pem in Pem::iter_from_buffer(data) {
+            let pem = pem.ok()?;
+            match pem.label.as_str() {
+                "CERTIFICATE" => cert_der = Some(pem.contents),
+                "PRIVATE KEY" => key_der = Some(pem.contents),
+                _ => {}
+            }
+        }
+        Self::new(cert_der?, key_der?)
+    }
+
+    fn is_fresh(&self) -> bool {
+        OffsetDateTime::now_utc() + LEAF_RENEW_BEFORE < self.not_after
+    }
+}
+
+/// Leaf certificates issued by the root CA, shared by every tunnel. Beyond `capacity` the least
+/// recently used are dropped from memory; all of them are also saved next to the CA, so a restart
+/// does not issue them again.
+pub(crate) struct LeafCache {
+    root_ca: RootCa,
+    capacity: usize,
+    state: Mutex<LeafCacheState>,
+}
+
+#[derive(Default)]
+struct LeafCacheState {
+    entries: HashMap<String, CachedLeaf>,
+    uses: u64,
+}
+
+struct CachedLeaf {
+    leaf: Arc<LeafCertificate>,
+    last_used: u64,
+}
+
+impl LeafCache {
+    pub(crate) fn new(root_ca: RootCa, capacity: usize) -> Self {
+        Self {
+            root_ca,
+            capacity,
+            state: Mutex::new(LeafCacheState::default()),
+        }
+    }
+
+    /// A certificate covering `names` that is not about to expire, the first name being the common name.
+    pub(crate) fn get(&self, names: &[&str]) -> Result<Arc<LeafCertificate>, anyhow::Error> {
+        let key = names.iter().map(|name| name.to_ascii_lowercase()).collect::<Vec<_>>().join(",");
+        if let Some(leaf) = self.lookup(&key) {
+            return Ok(leaf);
+        }
+
+        let path = self.leaf_path(names, &key);
+        let saved = fs::read(&path).ok().and_then(|data| LeafCertificate::from_pem(&data));
+        let leaf = match saved.filter(LeafCertificate::is_fresh) {
+            Some(leaf) => leaf,
+            None => {
+                let (cert, key_pair) = self.root_ca.issue_certificate(names)?;
+                if let Err(e) = save_leaf(&path, &cert, &key_pair) {
+                    eprintln!("Could not save the certificate for {}: {}", &key, e);
+                }
+                LeafCertificate::new(cert.der().to_vec(), key_pair.serialize_der())
+                    .ok_or_else(|| anyhow::anyhow!("Issued an unreadable certificate for {}", &key))?
+            }
+        };
+
+        let leaf = Arc::new(leaf);
+        self.insert(key, leaf.clone());
+        Ok(leaf)
+    }
+
+    fn lookup(&self, key: &str) -> Option<Arc<LeafCertificate>> {
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let uses = state.uses;
+
+        let cached = state.entries.get_mut(key)?;
+        if !cached.leaf.is_fresh() {
+            state.entries.remove(key);
+            return None;
+        }
+        cached.last_used = uses;
+        Some(cached.leaf.clone())
+    }
+
+    fn insert(&self, key: String, leaf: Arc<LeafCertificate>) {
+        let mut state = self.state.lock().unwrap();
+        state.uses += 1;
+        let last_used = state.uses;
+        state.entries.insert(key, CachedLeaf { leaf, last_used });
+
+        while state.entries.len() > self.capacity.max(1) {
+            let Some(oldest) = state.entries.iter().min_by_key(|(_, cached)| cached.last_used).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+    }
+
+    /// Named after the first name, with a hash of the whole set so different sets do not collide.
+    fn leaf_path(&self, names: &[&str], key: &str) -> PathBuf {
+        let readable_name = names
+            .first()
+            .unwrap_or(&"")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+            .collect::<String>();
+        let hash = hex(&Sha256::digest(key.as_bytes())[..8]);
+        self.root_ca.dir.join(LEAF_DIR).join(format!("{}-{}.pem", readable_name, hash))
+    }
+}
+
+fn save_leaf(path: &Path, cert: &Certificate, key_pair: &KeyPair) -> Result<(), std::io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_private(path, format!("{}{}", cert.pem(), key_pair.serialize_pem()).as_bytes())
+}
+
+fn generate_root_ca() -> Result<(Certificate, KeyPair), rcgen::Error> {
+    let mut params = CertificateParams::default();
+
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+    ];
+
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, "DagProxy Root CA");
+    distinguished_name.push(DnType::OrganizationName, "DagProxy");
+    params.distinguished_name = distinguished_name;
+
+    let key_pair = KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    Ok((cert, key_pair))
+}
+
+fn get_issuer_from_pem(pem_str: &str, private_key_pem: &str) -> Result<Issuer<'static, KeyPair>, rcgen::Error> {
+    let signing_key = KeyPair::from_pem(private_key_pem)?;
+    Issuer::from_ca_cert_pem(pem_str, signing_key)
+}
+
+/// Defaults to `dagproxy` in the user's data directory. There is no shared fallback such as
+/// the temporary directory, where anyone could have planted a key.
+fn ca_dir(config: &CaConfig) -> Result<PathBuf, anyhow::Error> {
+    if let Some(dir) = &config.dir {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let data_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+    };
+    data_dir
+        .map(|data_dir| data_dir.join("dagproxy"))
+        .ok_or_else(|| anyhow!("No data directory for the CA, set ca.dir, XDG_DATA_HOME or HOME"))
+}
+
+/// Refuses a CA key that is not owned by the current user or that others can read.
+#[cfg(unix)]
+fn check_private(path: &Path) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path)?;
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(anyhow!("{} is not owned by the current user", path.display()));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!("{} can be read by other users, chmod it to 0600", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_path: &Path) -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+/// Writes a file only the current user can read.
+fn write_private(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cert::{LeafCache, RootCa};
+    use crate::config::CaConfig;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_root_ca_is_persisted_and_issues_certificates() {
+        let dir = std::env::temp_dir().join(format!("dagproxy-ca-test-{}", std::process::id()));
+        let config = CaConfig { dir: Some(dir.to_string_lossy().into_owned()), ..CaConfig::default() };
+
+        let generated = RootCa::load_or_generate(&config).unwrap();
+        let loaded = RootCa::load_or_generate(&config).unwrap();
+        assert_eq!(generated.pem(), loaded.pem());
+
+        let (cert, _) = loaded.issue_certificate(&["intranet.example.com"]).unwrap();
+        assert!(cert.pem().starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(loaded.issue_certificate(&["10.0.0.1"]).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_root_ca_is_not_replaced_when_a_file_is_missing() {
+        let dir = std::env::temp_dir().join(format!("dagproxy-ca-missing-test-{}", std::process::id()));
+        let config = CaConfig { dir: Some(dir.to_string_lossy().into_owned()), ..CaConfig::default() };
+
+        RootCa::load_or_generate(&config).unwrap();
+        let key = std::fs::read(dir.join("dagproxy-ca.key")).unwrap();
+        std::fs::remove_file(dir.join("dagproxy-ca.pem")).unwrap();
+
+        assert!(RootCa::load_or_generate(&config).is_err());
+        assert_eq!(std::fs::read(dir.join("dagproxy-ca.key")).unwrap(), key);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_leaf_cache_reuses_certificates_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("dagproxy-leaf-test-{}", std::process::id()));
+        let config = CaConfig { dir: Some(dir.to_string_lossy().into_owned()), ..CaConfig::default() };
+
+        let cache = LeafCache::new(RootCa::load_or_generate(&config).unwrap(), 1);
+        let first = cache.get(&["a.example.com"]).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(&["A.example.com"]).unwrap()));
+
+        // Evicts a.example.com, which is then read back from disk rather than issued again.
+        let other = cache.get(&["b.example.com"]).unwrap();
+        assert_ne!(first.cert_der, other.cert_der);
+        let reloaded = cache.get(&["a.example.com"]).unwrap();
+        assert!(!Arc::ptr_eq(&first, &reloaded));
+        assert_eq!(first.cert_der, reloaded.cert_der);
+
+        let restarted = LeafCache::new(RootCa::load_or_generate(&config).unwrap(), 8);
+        assert_eq!(first.cert_der, restarted.get(&["a.example.com"]).unwrap().cert_der);
+        assert_ne!(first.cert_der, restarted.get(&["a.example.com", "www.example.com"]).unwrap().cert_der);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
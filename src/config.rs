@@ -1,6 +1,8 @@
 use crate::NoProxyValue;
 use netaddr2::Netv4Addr;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum SubNetKey {
@@ -11,7 +13,11 @@ pub enum SubNetKey {
 #[derive(Clone, PartialEq, Debug)]
 pub enum ProxyConfig {
     Direct,
-    Proxy { host: String, port: u32, no_proxy: Vec<NoProxyValue> }
+    /// `upstreams` are tried as `policy` says, moving on to the next one when a proxy fails.
+    Proxy { upstreams: Vec<UpstreamProxy>, policy: UpstreamPolicy, kind: ProxyKind, no_proxy: Vec<NoProxyValue>, auth: Vec<ProxyAuth>, spn: ProxySpn },
+    /// Routes chosen by a Proxy Auto-Config script at `url` (`http(s)://` or a local file).
+    /// `auth` and `spn` apply to the HTTP proxies it returns.
+    Pac { url: String, auth: Vec<ProxyAuth>, spn: ProxySpn },
 }
 impl Default for ProxyConfig {
     fn default() -> Self {
@@ -19,11 +25,260 @@ impl Default for ProxyConfig {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct UpstreamProxy {
+    pub host: String,
+    pub port: u32,
+}
+
+/// Which of several upstream proxies a new connection tries first.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum UpstreamPolicy {
+    /// In the configured order, the next ones only when the first fails.
+    #[default]
+    Failover,
+    RoundRobin,
+    /// The one with the fewest open tunnels.
+    LeastConnections,
+}
+
+/// WPAD auto-discovery on a subnet: a PAC file found with DNS replaces its configured entry,
+/// which is kept as the fallback. `auth` and `spn` apply to the proxies the PAC file returns.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Discovery {
+    pub auth: Vec<ProxyAuth>,
+    pub spn: ProxySpn,
+    /// Parent domain down to which the search domains are devolved, none when only they are tried.
+    pub devolve_to: Option<String>,
+}
+
+/// Protocol spoken by the upstream proxy. `auth` and `spn` only apply to HTTP proxies.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum ProxyKind {
+    #[default]
+    Http,
+    Socks4a { user_id: String },
+    Socks5(Option<SocksCredentials>),
+}
+
+/// How the Kerberos service principal of the proxy is derived.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum ProxySpn {
+    /// `HTTP/<proxy host>` as configured.
+    #[default]
+    Host,
+    /// `HTTP/<canonical name>`, following CNAMEs and reverse DNS of the proxy address.
+    Canonical,
+    Explicit(String),
+}
+
+/// Authentication schemes to try against the upstream proxy, in order of preference.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ProxyAuth {
+    /// Kerberos with the session's credentials, or with a dedicated keytab login.
+    Negotiate(Option<KeytabLogin>),
+    Ntlm(ProxyCredentials),
+    Digest(ProxyCredentials),
+    Basic(ProxyCredentials),
+    /// Retry once without credentials.
+    None,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct KeytabLogin {
+    pub principal: String,
+    pub keytab: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub domain: Option<String>,
+    pub password: Secret,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Secret {
+    Plain(String),
+    Env(String),
+}
+
+impl Secret {
+    pub fn resolve(&self) -> Result<String, anyhow::Error> {
+        match self {
+            Secret::Plain(value) => Ok(value.clone()),
+            Secret::Env(variable) => std::env::var(variable)
+                .map_err(|e| anyhow::anyhow!("Could not read proxy password from ${}: {}", variable, e)),
+        }
+    }
+}
+
+/// Credential cache monitoring and renewal.
+#[derive(Clone, PartialEq, Debug)]
+pub struct KerberosConfig {
+    pub check_interval: Duration,
+    pub warn_before: Duration,
+    /// Command run when the TGT is about to expire, e.g. `["kinit", "-R"]`.
+    pub renew_command: Option<Vec<String>>,
+    pub keytab: Option<String>,
+    pub principal: Option<String>,
+    /// Credential cache collection for keytab logins, defaults to a per-user directory under the temp dir.
+    pub private_cache_dir: Option<String>,
+}
+
+impl Default for KerberosConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(60),
+            warn_before: Duration::from_secs(30 * 60),
+            renew_command: None,
+            keytab: None,
+            principal: None,
+            private_cache_dir: None,
+        }
+    }
+}
+
+/// Keep-alive connections kept open to each upstream proxy.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PoolConfig {
+    pub max_idle: usize,
+    /// Connections opened ahead of time so the next request does not wait for a TCP handshake.
+    pub prewarm: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 8,
+            prewarm: 2,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Where the local root CA used for TLS interception is kept.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CaConfig {
+    /// Defaults to `dagproxy` in the user's data directory.
+    pub dir: Option<String>,
+    /// Leaf certificates kept in memory, the others are read back from the CA directory.
+    pub leaf_cache_size: usize,
+}
+
+impl Default for CaConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            leaf_cache_size: 1024,
+        }
+    }
+}
+
+/// TLS interception of `CONNECT` tunnels, limited to the hosts listed.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MitmConfig {
+    /// Host names, or `*.example.com` for any subdomain.
+    pub hosts: Vec<String>,
+    /// Headers set on every intercepted request.
+    pub headers: Vec<(String, String)>,
+}
+
+/// Background probing of every configured upstream proxy.
+#[derive(Clone, PartialEq, Debug)]
+pub struct HealthCheckConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// `host:port` reached through each proxy with an authenticated `CONNECT`.
+    pub probe_host: String,
+    /// Connect directly when every proxy of the active subnet is down.
+    pub fallback_direct: bool,
+}
+
+/// SOCKS clients are accepted on the main port; `port` adds a dedicated listener.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SocksConfig {
+    pub port: Option<u32>,
+    /// Username/password clients must present, no authentication when absent.
+    pub credentials: Option<SocksCredentials>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SocksCredentials {
+    pub username: String,
+    pub password: Secret,
+}
+
+/// Listener for connections redirected to dagproxy by the firewall.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TransparentConfig {
+    pub listen: String,
+    pub port: u32,
+    pub mode: TransparentMode,
+    /// Destination ports sent to the listener by the generated firewall rules.
+    pub ports: Vec<u16>,
+    /// Firewall mark and routing table steering TPROXY traffic to the local socket.
+    pub mark: u32,
+    pub route_table: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TransparentMode {
+    /// NAT `REDIRECT`, the original destination is read back with `SO_ORIGINAL_DST`.
+    #[default]
+    Redirect,
+    /// `TPROXY` without NAT, the original destination is the local address of the accepted socket.
+    Tproxy,
+}
+
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
 #[derive(Clone, PartialEq)]
 pub struct Config {
     pub port: u32,
+    /// Largest client request head accepted, answered with a `431` beyond that.
+    pub max_header_size: usize,
     pub subnets: Vec<(SubNetKey, ProxyConfig)>,
+    /// Subnets whose proxy is discovered with WPAD, their entry in `subnets` being the fallback.
+    pub discover: HashMap<SubNetKey, Discovery>,
+    pub kerberos: KerberosConfig,
+    pub upstream_pool: PoolConfig,
+    pub socks: Option<SocksConfig>,
+    pub transparent: Option<TransparentConfig>,
+    pub ca: CaConfig,
+    pub mitm: Option<MitmConfig>,
+    pub health_check: Option<HealthCheckConfig>,
+}
+impl Config {
+    /// Keytab logins configured across all subnets.
+    pub fn keytab_logins(&self) -> Vec<KeytabLogin> {
+        let mut logins: Vec<KeytabLogin> = Vec::new();
+        for proxy_auth in self.proxy_auth() {
+            if let ProxyAuth::Negotiate(Some(login)) = proxy_auth
+                && !logins.contains(login)
+            {
+                logins.push(login.clone());
+            }
+        }
+        logins
+    }
+
+    /// Whether a subnet authenticates with `Negotiate` from the session's own credential cache.
+    pub fn uses_session_ccache(&self) -> bool {
+        self.proxy_auth().any(|proxy_auth| matches!(proxy_auth, ProxyAuth::Negotiate(None)))
+    }
+
+    fn proxy_auth(&self) -> impl Iterator<Item = &ProxyAuth> {
+        let subnet_auth = self.subnets.iter().filter_map(|(_, proxy_config)| match proxy_config {
+            ProxyConfig::Proxy { auth, .. } | ProxyConfig::Pac { auth, .. } => Some(auth),
+            ProxyConfig::Direct => None,
+        });
+        let discovery_auth = self.discover.values().map(|discovery| &discovery.auth);
+        subnet_auth.chain(discovery_auth).flatten()
+    }
 }
+
 impl Default for Config {
     fn default() -> Self {
         let mut subnets = Vec::new();
@@ -31,22 +286,28 @@ impl Default for Config {
         subnets.push(
             (SubNetKey::Subnet(Netv4Addr::from_str("10.80.0.0/16").unwrap()),
             ProxyConfig::Proxy {
-                host: "proxygate.onemrva.priv".to_owned(),
-                port: 8888,
+                upstreams: vec![UpstreamProxy { host: "proxygate.onemrva.priv".to_owned(), port: 8888 }],
+                policy: UpstreamPolicy::Failover,
+                kind: ProxyKind::Http,
                 no_proxy: "localhost,rvaonem.priv,rvaonem.fgov.be,169.254.169.254,cloud.rvadc.be,onemrva.priv,teams.microsoft.com,google.com".split(",").map(|host| {
                     NoProxyValue::from_str(host).expect(format!("Invalid no proxy host: {}", &host).as_str())
-                }).collect::<Vec<_>>()
+                }).collect::<Vec<_>>(),
+                auth: vec![ProxyAuth::Negotiate(None)],
+                spn: ProxySpn::Host,
             })
         );
 
         subnets.push(
             (SubNetKey::Subnet(Netv4Addr::from_str("10.130.0.0/16").unwrap()),
             ProxyConfig::Proxy {
-                host: "proxygate.onemrva.priv".to_owned(),
-                port: 8888,
+                upstreams: vec![UpstreamProxy { host: "proxygate.onemrva.priv".to_owned(), port: 8888 }],
+                policy: UpstreamPolicy::Failover,
+                kind: ProxyKind::Http,
                 no_proxy: "localhost,rvaonem.priv,rvaonem.fgov.be,169.254.169.254,cloud.rvadc.be,onemrva.priv".split(",").map(|host| {
                     NoProxyValue::from_str(host).expect(format!("Invalid no proxy host: {}", &host).as_str())
-                }).collect::<Vec<_>>()
+                }).collect::<Vec<_>>(),
+                auth: vec![ProxyAuth::Negotiate(None)],
+                spn: ProxySpn::Host,
             })
         );
 
@@ -54,7 +315,16 @@ impl Default for Config {
 
         Self {
             port: 3333,
-            subnets
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            subnets,
+            discover: HashMap::new(),
+            kerberos: KerberosConfig::default(),
+            upstream_pool: PoolConfig::default(),
+            socks: None,
+            transparent: None,
+            ca: CaConfig::default(),
+            mitm: None,
+            health_check: None,
         }
     }
 }
\ No newline at end of file
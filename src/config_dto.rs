@@ -1,34 +1,128 @@
-use crate::config::{Config, ProxyConfig, SubNetKey};
+use crate::config::{CaConfig, Config, DEFAULT_MAX_HEADER_SIZE, Discovery, HealthCheckConfig, KerberosConfig, KeytabLogin, MitmConfig, PoolConfig, ProxyAuth, ProxyKind, ProxySpn, ProxyConfig, ProxyCredentials, Secret, SocksConfig, SocksCredentials, SubNetKey, TransparentConfig, TransparentMode, UpstreamPolicy, UpstreamProxy};
 use netaddr2::Netv4Addr;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
+use std::time::Duration;
 use crate::NoProxyValue;
+use anyhow::anyhow;
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct ConfigDto {
     pub port: u32,
+    pub max_header_size: Option<usize>,
     pub subnets: Vec<ProxyConfigDto>,
+    pub kerberos: Option<KerberosConfigDto>,
+    pub upstream_pool: Option<PoolConfigDto>,
+    pub socks: Option<SocksConfigDto>,
+    pub transparent: Option<TransparentConfigDto>,
+    pub ca: Option<CaConfigDto>,
+    pub mitm: Option<MitmConfigDto>,
+    pub health_check: Option<HealthCheckConfigDto>,
 }
 
-impl Into<Config> for ConfigDto {
-    fn into(self) -> Config {
-        let subnets = self.subnets.iter().map(|subnet| match subnet {
+impl TryFrom<ConfigDto> for Config {
+    type Error = anyhow::Error;
+
+    fn try_from(dto: ConfigDto) -> anyhow::Result<Config> {
+        let subnets = dto.subnets.iter().map(|subnet| Ok(match subnet {
             ProxyConfigDto::Direct => (SubNetKey::Default, ProxyConfig::Direct),
             ProxyConfigDto::Proxy(subnet_dto) => (
                 SubNetKey::Subnet(Netv4Addr::from_str(subnet_dto.ip_range.as_str()).unwrap()),
                 ProxyConfig::Proxy {
-                    host: subnet_dto.proxy_host.clone(),
-                    port: subnet_dto.proxy_port,
+                    upstreams: upstream_proxies(&subnet_dto.proxy_host, subnet_dto.proxy_port, &subnet_dto.upstreams)?,
+                    policy: subnet_dto.policy.into(),
+                    kind: subnet_dto.kind.clone().into(),
                     no_proxy: subnet_dto.no_proxy.iter()
                         .map(|no_proxy| NoProxyValue::from_str(no_proxy.as_str()).unwrap())
                         .collect::<Vec<_>>(),
+                    auth: proxy_auth(&subnet_dto.auth),
+                    spn: proxy_spn(&subnet_dto.spn, &subnet_dto.spn_mode),
+                },
+            ),
+            ProxyConfigDto::Pac(subnet_dto) => (
+                SubNetKey::Subnet(Netv4Addr::from_str(subnet_dto.ip_range.as_str()).unwrap()),
+                ProxyConfig::Pac {
+                    url: subnet_dto.url.clone(),
+                    auth: proxy_auth(&subnet_dto.auth),
+                    spn: proxy_spn(&subnet_dto.spn, &subnet_dto.spn_mode),
                 },
             ),
-        }).collect::<Vec<_>>();
+            ProxyConfigDto::Discover(subnet_dto) => (
+                SubNetKey::Subnet(Netv4Addr::from_str(subnet_dto.ip_range.as_str()).unwrap()),
+                match &subnet_dto.fallback {
+                    Some(fallback) => ProxyConfig::Proxy {
+                        upstreams: upstream_proxies(&fallback.proxy_host, fallback.proxy_port, &fallback.upstreams)?,
+                        policy: fallback.policy.into(),
+                        kind: fallback.kind.clone().into(),
+                        no_proxy: fallback.no_proxy.iter()
+                            .map(|no_proxy| NoProxyValue::from_str(no_proxy.as_str()).unwrap())
+                            .collect::<Vec<_>>(),
+                        auth: proxy_auth(&subnet_dto.auth),
+                        spn: proxy_spn(&subnet_dto.spn, &subnet_dto.spn_mode),
+                    },
+                    None => ProxyConfig::Direct,
+                },
+            ),
+        })).collect::<anyhow::Result<Vec<_>>>()?;
+        let discover = dto.subnets.iter().filter_map(|subnet| match subnet {
+            ProxyConfigDto::Discover(subnet_dto) => Some((
+                SubNetKey::Subnet(Netv4Addr::from_str(subnet_dto.ip_range.as_str()).unwrap()),
+                Discovery {
+                    auth: proxy_auth(&subnet_dto.auth),
+                    spn: proxy_spn(&subnet_dto.spn, &subnet_dto.spn_mode),
+                    devolve_to: subnet_dto.devolve_to.as_ref()
+                        .map(|domain| domain.trim_matches('.').to_ascii_lowercase()),
+                },
+            )),
+            _ => None,
+        }).collect::<HashMap<_, _>>();
 
-        Config {
-            port: self.port,
+        Ok(Config {
+            port: dto.port,
+            max_header_size: dto.max_header_size.unwrap_or(DEFAULT_MAX_HEADER_SIZE),
             subnets,
-        }
+            discover,
+            kerberos: dto.kerberos.map(|kerberos| kerberos.into()).unwrap_or_default(),
+            upstream_pool: dto.upstream_pool.map(|upstream_pool| upstream_pool.into()).unwrap_or_default(),
+            socks: dto.socks.map(|socks| socks.into()),
+            transparent: dto.transparent.map(|transparent| transparent.into()),
+            ca: dto.ca.map(|ca| ca.into()).unwrap_or_default(),
+            mitm: dto.mitm.map(|mitm| mitm.into()),
+            health_check: dto.health_check.map(|health_check| health_check.into()),
+        })
+    }
+}
+
+fn proxy_auth(auth_dto: &[ProxyAuthDto]) -> Vec<ProxyAuth> {
+    if auth_dto.is_empty() {
+        vec![ProxyAuth::Negotiate(None)]
+    } else {
+        auth_dto.iter().map(|auth| auth.into()).collect::<Vec<_>>()
+    }
+}
+
+/// `proxy_host`/`proxy_port` first, then the `upstreams` list.
+fn upstream_proxies(proxy_host: &Option<String>, proxy_port: Option<u32>, upstreams: &[UpstreamProxyDto]) -> anyhow::Result<Vec<UpstreamProxy>> {
+    let first = match (proxy_host, proxy_port) {
+        (Some(host), Some(port)) => Some(UpstreamProxy { host: host.clone(), port }),
+        (Some(host), None) => return Err(anyhow!("Missing proxy_port for proxy_host {}", host)),
+        (None, _) => None,
+    };
+    let upstreams = first
+        .into_iter()
+        .chain(upstreams.iter().map(|upstream| UpstreamProxy { host: upstream.host.clone(), port: upstream.port }))
+        .collect::<Vec<_>>();
+    if upstreams.is_empty() {
+        return Err(anyhow!("A proxy needs proxy_host and proxy_port, or upstreams"));
+    }
+    Ok(upstreams)
+}
+
+fn proxy_spn(spn: &Option<String>, spn_mode: &Option<SpnModeDto>) -> ProxySpn {
+    match (spn, spn_mode) {
+        (Some(spn), _) => ProxySpn::Explicit(spn.clone()),
+        (None, Some(SpnModeDto::Canonical)) => ProxySpn::Canonical,
+        (None, Some(SpnModeDto::Host) | None) => ProxySpn::Host,
     }
 }
 
@@ -36,12 +130,342 @@ impl Into<Config> for ConfigDto {
 pub enum ProxyConfigDto {
     Direct,
     Proxy(ProxySubnet),
+    Pac(PacSubnet),
+    Discover(DiscoverSubnet),
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct ProxySubnet {
     pub ip_range: String,
-    pub proxy_host: String,
-    pub proxy_port: u32,
+    pub proxy_host: Option<String>,
+    pub proxy_port: Option<u32>,
+    /// Further proxies of the site, after `proxy_host`.
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamProxyDto>,
+    #[serde(default)]
+    pub policy: UpstreamPolicyDto,
+    #[serde(default)]
+    pub kind: ProxyKindDto,
+    pub no_proxy: Vec<String>,
+    #[serde(default)]
+    pub auth: Vec<ProxyAuthDto>,
+    /// Explicit Kerberos service principal of the proxy, e.g. `HTTP/proxy.example.com@EXAMPLE.COM`.
+    pub spn: Option<String>,
+    pub spn_mode: Option<SpnModeDto>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PacSubnet {
+    pub ip_range: String,
+    /// `http://`, `https://` or `file://` URL, or a local path.
+    pub url: String,
+    #[serde(default)]
+    pub auth: Vec<ProxyAuthDto>,
+    pub spn: Option<String>,
+    pub spn_mode: Option<SpnModeDto>,
+}
+
+/// Proxy found with WPAD on each network change, or the static `fallback` (direct when absent).
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct DiscoverSubnet {
+    pub ip_range: String,
+    pub fallback: Option<StaticProxy>,
+    #[serde(default)]
+    pub auth: Vec<ProxyAuthDto>,
+    pub spn: Option<String>,
+    pub spn_mode: Option<SpnModeDto>,
+    /// Also look for `wpad.<parent>` of the search domains, down to this domain.
+    pub devolve_to: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct StaticProxy {
+    pub proxy_host: Option<String>,
+    pub proxy_port: Option<u32>,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamProxyDto>,
+    #[serde(default)]
+    pub policy: UpstreamPolicyDto,
+    #[serde(default)]
+    pub kind: ProxyKindDto,
+    #[serde(default)]
     pub no_proxy: Vec<String>,
 }
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct UpstreamProxyDto {
+    pub host: String,
+    pub port: u32,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+pub enum UpstreamPolicyDto {
+    #[default]
+    Failover,
+    RoundRobin,
+    LeastConnections,
+}
+
+impl From<UpstreamPolicyDto> for UpstreamPolicy {
+    fn from(policy_dto: UpstreamPolicyDto) -> Self {
+        match policy_dto {
+            UpstreamPolicyDto::Failover => UpstreamPolicy::Failover,
+            UpstreamPolicyDto::RoundRobin => UpstreamPolicy::RoundRobin,
+            UpstreamPolicyDto::LeastConnections => UpstreamPolicy::LeastConnections,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+pub enum ProxyKindDto {
+    #[default]
+    Http,
+    Socks4a {
+        user_id: Option<String>,
+    },
+    Socks5 {
+        username: Option<String>,
+        password: Option<String>,
+        password_env: Option<String>,
+    },
+}
+
+impl From<ProxyKindDto> for ProxyKind {
+    fn from(kind_dto: ProxyKindDto) -> Self {
+        match kind_dto {
+            ProxyKindDto::Http => ProxyKind::Http,
+            ProxyKindDto::Socks4a { user_id } => ProxyKind::Socks4a { user_id: user_id.unwrap_or_default() },
+            ProxyKindDto::Socks5 { username, password, password_env } => ProxyKind::Socks5(username.map(|username| SocksCredentials {
+                username,
+                password: match (password, password_env) {
+                    (Some(password), _) => Secret::Plain(password),
+                    (None, Some(variable)) => Secret::Env(variable),
+                    (None, None) => Secret::Env("DAGPROXY_PROXY_PASSWORD".to_owned()),
+                },
+            })),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub enum SpnModeDto {
+    Host,
+    Canonical,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub enum ProxyAuthDto {
+    Negotiate,
+    NegotiateKeytab(KeytabLoginDto),
+    Ntlm(ProxyCredentialsDto),
+    Digest(ProxyCredentialsDto),
+    Basic(ProxyCredentialsDto),
+    None,
+}
+
+impl From<&ProxyAuthDto> for ProxyAuth {
+    fn from(auth_dto: &ProxyAuthDto) -> Self {
+        match auth_dto {
+            ProxyAuthDto::Negotiate => ProxyAuth::Negotiate(None),
+            ProxyAuthDto::NegotiateKeytab(login) => ProxyAuth::Negotiate(Some(KeytabLogin {
+                principal: login.principal.clone(),
+                keytab: login.keytab.clone(),
+            })),
+            ProxyAuthDto::Ntlm(credentials) => ProxyAuth::Ntlm(credentials.into()),
+            ProxyAuthDto::Digest(credentials) => ProxyAuth::Digest(credentials.into()),
+            ProxyAuthDto::Basic(credentials) => ProxyAuth::Basic(credentials.into()),
+            ProxyAuthDto::None => ProxyAuth::None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct KeytabLoginDto {
+    pub principal: String,
+    pub keytab: String,
+}
+
+/// `password` is used verbatim, otherwise it is read from the `password_env` environment variable
+/// (`DAGPROXY_PROXY_PASSWORD` by default) when authenticating.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ProxyCredentialsDto {
+    pub username: String,
+    pub domain: Option<String>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+}
+
+impl From<&ProxyCredentialsDto> for ProxyCredentials {
+    fn from(credentials_dto: &ProxyCredentialsDto) -> Self {
+        let password = match (&credentials_dto.password, &credentials_dto.password_env) {
+            (Some(password), _) => Secret::Plain(password.clone()),
+            (None, Some(variable)) => Secret::Env(variable.clone()),
+            (None, None) => Secret::Env("DAGPROXY_PROXY_PASSWORD".to_owned()),
+        };
+
+        ProxyCredentials {
+            username: credentials_dto.username.clone(),
+            domain: credentials_dto.domain.clone(),
+            password,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct KerberosConfigDto {
+    pub check_interval_secs: Option<u64>,
+    pub warn_before_minutes: Option<u64>,
+    pub renew_command: Option<Vec<String>>,
+    pub keytab: Option<String>,
+    pub principal: Option<String>,
+    pub private_cache_dir: Option<String>,
+}
+
+impl From<KerberosConfigDto> for KerberosConfig {
+    fn from(kerberos_dto: KerberosConfigDto) -> Self {
+        let default = KerberosConfig::default();
+        KerberosConfig {
+            check_interval: kerberos_dto.check_interval_secs.map(Duration::from_secs).unwrap_or(default.check_interval),
+            warn_before: kerberos_dto.warn_before_minutes.map(|minutes| Duration::from_secs(minutes * 60)).unwrap_or(default.warn_before),
+            renew_command: kerberos_dto.renew_command,
+            keytab: kerberos_dto.keytab,
+            principal: kerberos_dto.principal,
+            private_cache_dir: kerberos_dto.private_cache_dir,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct PoolConfigDto {
+    pub max_idle: Option<usize>,
+    pub prewarm: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl From<PoolConfigDto> for PoolConfig {
+    fn from(pool_dto: PoolConfigDto) -> Self {
+        let default = PoolConfig::default();
+        PoolConfig {
+            max_idle: pool_dto.max_idle.unwrap_or(default.max_idle),
+            prewarm: pool_dto.prewarm.unwrap_or(default.prewarm),
+            idle_timeout: pool_dto.idle_timeout_secs.map(Duration::from_secs).unwrap_or(default.idle_timeout),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct SocksConfigDto {
+    pub port: Option<u32>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+}
+
+impl From<SocksConfigDto> for SocksConfig {
+    fn from(socks_dto: SocksConfigDto) -> Self {
+        let credentials = socks_dto.username.map(|username| SocksCredentials {
+            username,
+            password: match (socks_dto.password, socks_dto.password_env) {
+                (Some(password), _) => Secret::Plain(password),
+                (None, Some(variable)) => Secret::Env(variable),
+                (None, None) => Secret::Env("DAGPROXY_SOCKS_PASSWORD".to_owned()),
+            },
+        });
+
+        SocksConfig {
+            port: socks_dto.port,
+            credentials,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct TransparentConfigDto {
+    pub listen: Option<String>,
+    pub port: u32,
+    pub mode: Option<TransparentModeDto>,
+    pub ports: Option<Vec<u16>>,
+    pub mark: Option<u32>,
+    pub route_table: Option<u32>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub enum TransparentModeDto {
+    Redirect,
+    Tproxy,
+}
+
+impl From<TransparentConfigDto> for TransparentConfig {
+    fn from(transparent_dto: TransparentConfigDto) -> Self {
+        let mode = match transparent_dto.mode {
+            Some(TransparentModeDto::Tproxy) => TransparentMode::Tproxy,
+            Some(TransparentModeDto::Redirect) | None => TransparentMode::Redirect,
+        };
+        // TPROXY hands over forwarded traffic, which does not arrive on the loopback interface.
+        let default_listen = match mode {
+            TransparentMode::Redirect => "127.0.0.1",
+            TransparentMode::Tproxy => "::",
+        };
+
+        TransparentConfig {
+            listen: transparent_dto.listen.unwrap_or_else(|| default_listen.to_owned()),
+            port: transparent_dto.port,
+            mode,
+            ports: transparent_dto.ports.unwrap_or_else(|| vec![80, 443]),
+            mark: transparent_dto.mark.unwrap_or(1),
+            route_table: transparent_dto.route_table.unwrap_or(100),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct CaConfigDto {
+    pub dir: Option<String>,
+    pub leaf_cache_size: Option<usize>,
+}
+
+impl From<CaConfigDto> for CaConfig {
+    fn from(ca_dto: CaConfigDto) -> Self {
+        let default = CaConfig::default();
+        CaConfig {
+            dir: ca_dto.dir,
+            leaf_cache_size: ca_dto.leaf_cache_size.unwrap_or(default.leaf_cache_size),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct MitmConfigDto {
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl From<MitmConfigDto> for MitmConfig {
+    fn from(mitm_dto: MitmConfigDto) -> Self {
+        MitmConfig {
+            hosts: mitm_dto.hosts,
+            headers: mitm_dto.headers.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct HealthCheckConfigDto {
+    pub probe_host: String,
+    pub interval_secs: Option<u64>,
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub fallback_direct: bool,
+}
+
+impl From<HealthCheckConfigDto> for HealthCheckConfig {
+    fn from(health_check_dto: HealthCheckConfigDto) -> Self {
+        HealthCheckConfig {
+            interval: Duration::from_secs(health_check_dto.interval_secs.unwrap_or(30)),
+            timeout: Duration::from_secs(health_check_dto.timeout_secs.unwrap_or(5)),
+            probe_host: health_check_dto.probe_host,
+            fallback_direct: health_check_dto.fallback_direct,
+        }
+    }
+}
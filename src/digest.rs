@@ -0,0 +1,181 @@
+use crate::hex::hex;
+use anyhow::anyhow;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// HTTP Digest (RFC 7616) client for a single `Proxy-Authenticate: Digest ...` challenge.
+pub struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: DigestAlgorithm,
+    qop_auth: bool,
+    stale: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DigestAlgorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl DigestAlgorithm {
+    fn hash(&self, data: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 | DigestAlgorithm::Md5Sess => hex(&Md5::digest(data.as_bytes())),
+            DigestAlgorithm::Sha256 | DigestAlgorithm::Sha256Sess => hex(&Sha256::digest(data.as_bytes())),
+        }
+    }
+
+    fn is_session(&self) -> bool {
+        matches!(self, DigestAlgorithm::Md5Sess | DigestAlgorithm::Sha256Sess)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Md5Sess => "MD5-sess",
+            DigestAlgorithm::Sha256 => "SHA-256",
+            DigestAlgorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+}
+
+impl DigestChallenge {
+    /// Parses the parameters following the `Digest` scheme name.
+    pub fn parse(challenge: &str) -> Result<Self, anyhow::Error> {
+        let params = parse_auth_params(challenge);
+
+        let algorithm = match params.get("algorithm").map(|algorithm| algorithm.to_ascii_uppercase()).as_deref() {
+            None | Some("MD5") => DigestAlgorithm::Md5,
+            Some("MD5-SESS") => DigestAlgorithm::Md5Sess,
+            Some("SHA-256") => DigestAlgorithm::Sha256,
+            Some("SHA-256-SESS") => DigestAlgorithm::Sha256Sess,
+            Some(other) => return Err(anyhow!("Unsupported Digest algorithm: {}", other)),
+        };
+
+        let qop_auth = match params.get("qop") {
+            Some(qop) => {
+                if !qop.split(',').any(|value| value.trim().eq_ignore_ascii_case("auth")) {
+                    return Err(anyhow!("Unsupported Digest qop: {}", qop));
+                }
+                true
+            }
+            None => false,
+        };
+
+        Ok(Self {
+            realm: params.get("realm").cloned().unwrap_or_default(),
+            nonce: params.get("nonce").cloned().ok_or_else(|| anyhow!("Digest challenge has no nonce"))?,
+            opaque: params.get("opaque").cloned(),
+            algorithm,
+            qop_auth,
+            stale: params.get("stale").is_some_and(|stale| stale.eq_ignore_ascii_case("true")),
+        })
+    }
+
+    /// Whether the proxy rejected a previous response only because its nonce expired.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Builds the `Proxy-Authorization` value for `method uri`.
+    pub fn authorization(&self, username: &str, password: &str, method: &str, uri: &str) -> String {
+        let cnonce = hex(&rand::random::<[u8; 16]>());
+        self.authorization_with_cnonce(username, password, method, uri, &cnonce)
+    }
+
+    fn authorization_with_cnonce(&self, username: &str, password: &str, method: &str, uri: &str, cnonce: &str) -> String {
+        const NONCE_COUNT: &str = "00000001";
+
+        let mut ha1 = self.algorithm.hash(&format!("{}:{}:{}", username, &self.realm, password));
+        if self.algorithm.is_session() {
+            ha1 = self.algorithm.hash(&format!("{}:{}:{}", ha1, &self.nonce, cnonce));
+        }
+        let ha2 = self.algorithm.hash(&format!("{}:{}", method, uri));
+
+        let response = if self.qop_auth {
+            self.algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, &self.nonce, NONCE_COUNT, cnonce, ha2))
+        } else {
+            self.algorithm.hash(&format!("{}:{}:{}", ha1, &self.nonce, ha2))
+        };
+
+        let mut authorization = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            username, &self.realm, &self.nonce, uri, self.algorithm.name(), response
+        );
+        if self.qop_auth {
+            authorization.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", NONCE_COUNT, cnonce));
+        }
+        if let Some(opaque) = &self.opaque {
+            authorization.push_str(&format!(", opaque=\"{}\"", opaque));
+        }
+        authorization
+    }
+}
+
+/// Parses `key=value` / `key="quoted, value"` pairs of an auth challenge. Keys are lowercased.
+pub fn parse_auth_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = params.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+
+        let key: String = std::iter::from_fn(|| chars.next_if(|c| *c != '=' && *c != ',')).collect();
+        if key.trim().is_empty() {
+            break;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            continue;
+        }
+
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let mut value = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    _ => value.push(c),
+                }
+            }
+            value
+        } else {
+            std::iter::from_fn(|| chars.next_if(|c| *c != ',')).collect::<String>().trim().to_owned()
+        };
+
+        parsed.insert(key.trim().to_ascii_lowercase(), value);
+    }
+
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::digest::{parse_auth_params, DigestChallenge};
+
+    #[test]
+    fn test_parse_auth_params() {
+        let params = parse_auth_params(r#"realm="Squid, proxy", nonce="abc", qop="auth,auth-int", stale=false"#);
+        assert_eq!(params["realm"], "Squid, proxy");
+        assert_eq!(params["nonce"], "abc");
+        assert_eq!(params["qop"], "auth,auth-int");
+        assert_eq!(params["stale"], "false");
+    }
+
+    // Example from RFC 2617 section 3.5.
+    #[test]
+    fn test_digest_md5_response() {
+        let challenge = DigestChallenge::parse(
+            r#"realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+
+        let authorization = challenge.authorization_with_cnonce("Mufasa", "Circle Of Life", "GET", "/dir/index.html", "0a4f113b");
+        assert!(authorization.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+        assert!(authorization.contains(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::config::{HealthCheckConfig, ProxyAuth, ProxyConfig, ProxyKind, ProxySpn, SubNetKey};
+use crate::http::{connect_over, join_host_port};
+use crate::socks::{connect_via_socks4a, connect_via_socks5};
+use crate::upstream_pool::UpstreamPool;
+use anyhow::anyhow;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// An upstream proxy along with what is needed to open an authenticated tunnel through it.
+#[derive(Clone)]
+struct ProbeTarget {
+    proxy_uri: String,
+    kind: ProxyKind,
+    auth: Vec<ProxyAuth>,
+    spn: ProxySpn,
+}
+
+/// Probes the upstream proxies of every subnet each `interval`, so that routing skips the dead
+/// ones instead of finding out on a client connection.
+pub(crate) fn watch_upstreams(config: HealthCheckConfig, subnets: &[(SubNetKey, ProxyConfig)], pool: UpstreamPool) {
+    let mut targets: Vec<ProbeTarget> = Vec::new();
+    for (_, proxy_config) in subnets {
+        if let ProxyConfig::Proxy { upstreams, kind, auth, spn, .. } = proxy_config {
+            for upstream in upstreams {
+                let proxy_uri = join_host_port(&upstream.host, upstream.port);
+                if !targets.iter().any(|target| target.proxy_uri == proxy_uri) {
+                    targets.push(ProbeTarget { proxy_uri, kind: kind.clone(), auth: auth.clone(), spn: spn.clone() });
+                }
+            }
+        }
+    }
+    if targets.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        loop {
+            interval.tick().await;
+            for target in &targets {
+                let (config, pool, target) = (config.clone(), pool.clone(), target.clone());
+                tokio::spawn(async move {
+                    let probed = probe(&target, &config).await;
+                    if !pool.record_probe(&target.proxy_uri, probed.as_ref().ok().copied()) {
+                        return;
+                    }
+                    match probed {
+                        Ok(latency) => println!("🩺 Upstream proxy {} is up ({} ms)", &target.proxy_uri, latency.as_millis()),
+                        Err(e) => eprintln!("🩺 Upstream proxy {} is down: {:#}", &target.proxy_uri, e),
+                    }
+                });
+            }
+        }
+    });
+}
+
+/// TCP connect to the proxy, whose time is the reported latency, then a tunnel to the probe host over
+/// that same connection. The pool is left alone, so probes neither use nor pre-warm its connections.
+async fn probe(target: &ProbeTarget, config: &HealthCheckConfig) -> Result<Duration, anyhow::Error> {
+    let started = Instant::now();
+    let stream = tokio::time::timeout(config.timeout, TcpStream::connect(&target.proxy_uri))
+        .await
+        .map_err(|_| anyhow!("Connecting timed out"))??;
+    let latency = started.elapsed();
+
+    let (proxy_uri, probe_host) = (target.proxy_uri.as_str(), config.probe_host.as_str());
+    let tunnel = async {
+        match &target.kind {
+            ProxyKind::Http => connect_over(stream, proxy_uri, probe_host, &target.auth, &target.spn, config.timeout).await,
+            ProxyKind::Socks4a { user_id } => connect_via_socks4a(stream, proxy_uri, probe_host, user_id).await,
+            ProxyKind::Socks5(credentials) => connect_via_socks5(stream, proxy_uri, probe_host, credentials.as_ref()).await,
+        }
+    };
+    tokio::time::timeout(config.timeout, tunnel)
+        .await
+        .map_err(|_| anyhow!("CONNECT to {} timed out", &config.probe_host))??;
+    Ok(latency)
+}
@@ -0,0 +1,4 @@
+/// Lowercase hex encoding, as used in Digest responses and test vectors.
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
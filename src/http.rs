@@ -1,13 +1,19 @@
 use std::io;
+use std::net::SocketAddr;
 use std::time::Duration;
 use anyhow::anyhow;
 use backon::ExponentialBuilder;
 use tokio::net::TcpStream;
 use backon::Retryable;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::kerberos::kerberos::negotiate_with_krb5;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::config::{ProxyAuth, ProxySpn};
+use crate::kerberos::kerberos::proxy_spn;
+use crate::kerberos_cache::TicketStatus;
+use crate::upstream_pool::{ConnectionAuth, PooledConnection, UpstreamPool};
+use crate::proxy_auth::{parse_challenges, select_authenticators, AuthChallenge, ProxyAuthenticator};
 
 pub(crate) const SUCCESS_CONNECT_RESPONSE: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+pub(crate) const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
 
 
 pub(crate) enum RequestType {
@@ -15,90 +21,1216 @@ pub(crate) enum RequestType {
     Other,
 }
 
-pub(crate) fn parse_host_from_request(data: &[u8]) -> Result<(RequestType, String), anyhow::Error> {
+pub(crate) fn request_type(data: &[u8]) -> RequestType {
     if data.starts_with(b"CONNECT ") {
-        let connect_body = String::from_utf8_lossy(&data);
-        let mut split = connect_body.split_whitespace();
-        let host = split.nth(1).unwrap().to_owned();
-        Ok((RequestType::Connect, host))
+        RequestType::Connect
     } else {
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut req = httparse::Request::new(&mut headers);
-        req.parse(data)?;
+        RequestType::Other
+    }
+}
 
-        let path = req.path.unwrap();
+/// Why a request head could not be read, each mapping to the status the client gets back.
+#[derive(Debug)]
+pub(crate) enum HeadError {
+    TooLarge(usize),
+    TooManyHeaders(usize),
+    Malformed(String),
+}
 
-        let default_port = {
-            if path.starts_with("https") {
-                "443".to_owned()
-            } else {
-                "80".to_owned()
+impl HeadError {
+    pub fn response(&self) -> Vec<u8> {
+        match self {
+            HeadError::TooLarge(_) | HeadError::TooManyHeaders(_) => {
+                error_response(431, "Request Header Fields Too Large", &[], &format!("{}\n", self))
             }
-        };
+            HeadError::Malformed(_) => error_response(400, "Bad Request", &[], &format!("{}\n", self)),
+        }
+    }
+}
 
-        let mut host = req
-            .headers
-            .iter()
-            .find_map(|header| {
-                if header.name == "Host" {
-                    Some(String::from_utf8_lossy(header.value).into_owned())
-                } else {
-                    None
-                }
-            })
-            .ok_or_else(|| anyhow::anyhow!("No host header found"))?;
+impl std::fmt::Display for HeadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeadError::TooLarge(max_size) => write!(f, "Message head exceeds {} bytes", max_size),
+            HeadError::TooManyHeaders(max_headers) => write!(f, "Message head has more than {} headers", max_headers),
+            HeadError::Malformed(reason) => write!(f, "Malformed message head: {}", reason),
+        }
+    }
+}
 
-        if !host.contains(":") {
-            host = format!("{}:{}", host, default_port)
-        };
+impl std::error::Error for HeadError {}
+
+/// Why the upstream side of a request failed, beyond plain I/O errors.
+#[derive(Debug)]
+pub(crate) enum UpstreamError {
+    /// None of the configured schemes got through. Keeps the proxy's challenges for the client.
+    ProxyAuthentication { challenges: Vec<String>, reason: String },
+    ProxyStatus(u16),
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::ProxyAuthentication { reason, .. } => write!(f, "Proxy authentication failed: {}", reason),
+            UpstreamError::ProxyStatus(status) => write!(f, "Received Error from proxy: {}", status),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Context of an error the proxy gave no answer at all to, not even part of a response head.
+#[derive(Debug)]
+struct Unanswered;
 
-        Ok((RequestType::Other, host))
+impl std::fmt::Display for Unanswered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Proxy did not answer")
     }
 }
 
+/// A `Connection: close` response with a plain text body.
+pub(crate) fn error_response(status: u16, reason: &str, headers: &[(String, String)], body: &str) -> Vec<u8> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Type".to_owned(), "text/plain; charset=utf-8".to_owned()));
+    headers.push(("Content-Length".to_owned(), body.len().to_string()));
+    headers.push(("Connection".to_owned(), "close".to_owned()));
+
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    write_headers(&mut response, &headers);
+    response.push_str(body);
+    response.into_bytes()
+}
+
+/// `200 OK` for a resource dagproxy serves itself. `HEAD` requests get the headers only.
+pub(crate) fn content_response(content_type: &str, body: &str, head_only: bool) -> Vec<u8> {
+    let headers = [
+        ("Content-Type".to_owned(), content_type.to_owned()),
+        ("Content-Length".to_owned(), body.len().to_string()),
+        ("Cache-Control".to_owned(), "no-cache".to_owned()),
+        ("Connection".to_owned(), "close".to_owned()),
+    ];
+
+    let mut response = "HTTP/1.1 200 OK\r\n".to_owned();
+    write_headers(&mut response, &headers);
+    if !head_only {
+        response.push_str(body);
+    }
+    response.into_bytes()
+}
+
+/// Tells the client why `target_host` could not be reached over `route`:
+/// `407` when proxy authentication failed, `504` when connecting timed out, `502` otherwise.
+/// A failed `Negotiate` also tells the state of the Kerberos ticket, the usual culprit.
+pub(crate) fn upstream_error_response(error: &anyhow::Error, route: &str, target_host: &str, ticket_status: &TicketStatus) -> Vec<u8> {
+    let mut body = format!("dagproxy could not reach {}\nRoute: {}\nError: {:#}\n", target_host, route, error);
+
+    match error.downcast_ref::<UpstreamError>() {
+        Some(UpstreamError::ProxyAuthentication { challenges, .. }) => {
+            if challenges.iter().any(|challenge| challenge.get(..9).is_some_and(|scheme| scheme.eq_ignore_ascii_case("Negotiate"))) {
+                body.push_str(&format!("Kerberos: {}\n", ticket_status));
+            }
+            let challenges = challenges
+                .iter()
+                .map(|challenge| ("Proxy-Authenticate".to_owned(), challenge.clone()))
+                .collect::<Vec<_>>();
+            error_response(407, "Proxy Authentication Required", &challenges, &body)
+        }
+        _ if error.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) => {
+            error_response(504, "Gateway Timeout", &[], &body)
+        }
+        _ => error_response(502, "Bad Gateway", &[], &body),
+    }
+}
+
+/// Collects the request head of a new client connection across as many reads as it takes.
+pub(crate) struct HeadAccumulator {
+    buffer: Vec<u8>,
+    max_size: usize,
+}
+
+impl HeadAccumulator {
+    pub fn new(max_size: usize) -> Self {
+        Self { buffer: Vec::new(), max_size }
+    }
+
+    /// Appends freshly read bytes and returns the head once the empty line ending it arrived.
+    /// Whatever the client sent after the head is left for [`HeadAccumulator::take_remaining`].
+    pub fn push(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, HeadError> {
+        let search_from = self.buffer.len().saturating_sub(3);
+        self.buffer.extend_from_slice(data);
+
+        // Empty lines before the start line are tolerated (RFC 9112 section 2.2).
+        let leading_newlines = self.buffer.iter().take_while(|byte| matches!(byte, b'\r' | b'\n')).count();
+        self.buffer.drain(..leading_newlines);
+
+        match find_head_end(&self.buffer, search_from.saturating_sub(leading_newlines)) {
+            Some(end) if end <= self.max_size => {
+                let rest = self.buffer.split_off(end);
+                Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+            }
+            Some(_) => Err(HeadError::TooLarge(self.max_size)),
+            None if self.buffer.len() > self.max_size => Err(HeadError::TooLarge(self.max_size)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn take_remaining(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// Position right after the empty line ending a message head.
+fn find_head_end(buffer: &[u8], search_from: usize) -> Option<usize> {
+    (search_from..buffer.len()).find_map(|position| match &buffer[position..] {
+        [b'\n', b'\r', b'\n', ..] => Some(position + 3),
+        [b'\n', b'\n', ..] => Some(position + 2),
+        _ => None,
+    })
+}
+
+/// Splits `host:port`, accepting bracketed IPv6 literals such as `[::1]:8080`.
+pub(crate) fn split_host_port(authority: &str) -> (&str, Option<&str>) {
+    if let Some(bracketed) = authority.strip_prefix('[')
+        && let Some((host, rest)) = bracketed.split_once(']')
+    {
+        return (host, rest.strip_prefix(':'));
+    }
+
+    match authority.rsplit_once(':') {
+        // More than one colon without brackets is a bare IPv6 literal.
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (authority, None),
+    }
+}
+
+/// Joins a host and port, bracketing IPv6 literals.
+pub(crate) fn join_host_port(host: &str, port: impl std::fmt::Display) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub(crate) async fn connect_with_retry(host: &str) -> Result<TcpStream, io::Error> {
-    (|| async { TcpStream::connect(&host).await }).retry(&ExponentialBuilder::default()
+    (|| connect_once(host, CONNECT_TIMEOUT))
+    .retry(&ExponentialBuilder::default()
         .with_min_delay(Duration::from_millis(500))
         .with_max_delay(Duration::from_secs(5))
         .with_max_times(5)).await
 }
 
+/// A single connection attempt, for hosts that have others to fall back on.
+pub(crate) async fn connect_once(host: &str, timeout: Duration) -> Result<TcpStream, io::Error> {
+    tokio::time::timeout(timeout, TcpStream::connect(host))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, format!("Connecting to {} timed out", host))))
+}
+
+
+fn header_values<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .iter()
+        .filter(move |(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Comma separated tokens of the `Connection` (and legacy `Proxy-Connection`) headers, lowercased.
+fn connection_options(headers: &[(String, String)]) -> Vec<String> {
+    header_values(headers, "Connection")
+        .chain(header_values(headers, "Proxy-Connection"))
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+fn persistent_connection(headers: &[(String, String)], minor_version: u8) -> bool {
+    let options = connection_options(headers);
+    if options.iter().any(|option| option == "close") {
+        false
+    } else {
+        minor_version >= 1 || options.iter().any(|option| option == "keep-alive")
+    }
+}
+
+/// Headers that only apply to a single hop and must not be forwarded.
+/// `Transfer-Encoding` is hop-by-hop too, but bodies are relayed with their framing unchanged.
+const HOP_BY_HOP_HEADERS: [&str; 5] = ["Connection", "Keep-Alive", "TE", "Trailer", "Upgrade"];
+
+/// Removes hop-by-hop headers, the headers listed in `Connection` and any `Proxy-*` header.
+/// With `keep_upgrade`, the `Upgrade` handshake is passed through.
+fn strip_hop_by_hop(headers: &mut Vec<(String, String)>, keep_upgrade: bool) {
+    let connection_options = connection_options(headers);
+    headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        !(HOP_BY_HOP_HEADERS.iter().any(|hop_by_hop| hop_by_hop.eq_ignore_ascii_case(&name))
+            || name.starts_with("proxy-")
+            || connection_options.contains(&name))
+            || (keep_upgrade && name == "upgrade")
+    });
+    if keep_upgrade {
+        headers.push(("Connection".to_owned(), "Upgrade".to_owned()));
+    }
+}
+
+fn write_headers(message: &mut String, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+}
+
+/// Adds `default_port` to an authority that has none.
+fn with_default_port(authority: &str, default_port: u16) -> String {
+    match split_host_port(authority) {
+        (host, Some(port)) => join_host_port(host, port),
+        (host, None) => join_host_port(host, default_port),
+    }
+}
+
+/// How the end of a message body is found.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Framing declared by `Transfer-Encoding` / `Content-Length`, `None` when neither is present.
+fn declared_body_length(headers: &[(String, String)]) -> Result<Option<BodyLength>, HeadError> {
+    if let Some(transfer_encoding) = header_values(headers, "Transfer-Encoding").last() {
+        let chunked = transfer_encoding
+            .rsplit(',')
+            .next()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        return Ok(Some(if chunked { BodyLength::Chunked } else { BodyLength::UntilClose }));
+    }
+
+    let mut content_lengths = header_values(headers, "Content-Length").flat_map(|value| value.split(',')).map(|value| value.trim());
+    match content_lengths.next() {
+        Some(content_length) => {
+            if content_lengths.any(|other| other != content_length) {
+                return Err(HeadError::Malformed("conflicting Content-Length headers".to_owned()));
+            }
+            let content_length = content_length
+                .parse()
+                .map_err(|_| HeadError::Malformed(format!("invalid Content-Length {}", content_length)))?;
+            Ok(Some(BodyLength::Fixed(content_length)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// A request read from a client of the proxy.
+#[derive(Clone)]
+pub(crate) struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub minor_version: u8,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    pub fn parse(head: &[u8]) -> Result<Self, HeadError> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => return Err(HeadError::Malformed("incomplete request head".to_owned())),
+            Err(httparse::Error::TooManyHeaders) => return Err(HeadError::TooManyHeaders(MAX_HEADERS)),
+            Err(e) => return Err(HeadError::Malformed(e.to_string())),
+        }
+
+        Ok(Self {
+            method: request.method.unwrap_or_default().to_owned(),
+            target: request.path.unwrap_or_default().to_owned(),
+            minor_version: request.version.unwrap_or(1),
+            headers: request
+                .headers
+                .iter()
+                .map(|header| (header.name.to_owned(), String::from_utf8_lossy(header.value).into_owned()))
+                .collect(),
+        })
+    }
+
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        header_values(&self.headers, name)
+    }
+
+    /// The `host:port` of a `CONNECT` request.
+    pub fn connect_target(&self) -> Result<String, HeadError> {
+        match split_host_port(&self.target) {
+            (host, Some(port)) if !host.is_empty() && !port.is_empty() => Ok(join_host_port(host, port)),
+            _ => Err(HeadError::Malformed(format!("invalid CONNECT target {}", &self.target))),
+        }
+    }
+
+    /// Rewrites an absolute-form target (`http://host/path`) to origin-form, as expected by
+    /// origin servers, and returns the `host:port` the request is meant for. An origin-form request
+    /// redirected by the firewall goes to the port of its `original_destination`, or to that address
+    /// when it has no `Host`.
+    pub fn rewrite_to_origin_form(&mut self, original_destination: Option<SocketAddr>) -> Result<String, HeadError> {
+        let Some((scheme, rest)) = self.target.split_once("://") else {
+            let default_port = original_destination.map_or(80, |destination| destination.port());
+            let host = self.header_values("Host").next().map(|host| with_default_port(host.trim(), default_port));
+            return match (host, original_destination) {
+                (Some(host), _) => Ok(host),
+                (None, Some(destination)) => {
+                    let address = destination.to_string();
+                    self.headers.insert(0, ("Host".to_owned(), address.clone()));
+                    Ok(address)
+                }
+                (None, None) => Err(HeadError::Malformed("no Host header".to_owned())),
+            };
+        };
+
+        let default_port = if scheme.eq_ignore_ascii_case("https") { 443 } else { 80 };
+        let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+        let authority = authority.rsplit('@').next().unwrap_or(authority).to_owned();
+        if authority.is_empty() {
+            return Err(HeadError::Malformed(format!("no host in request target {}", &self.target)));
+        }
+
+        let path = path.split('#').next().unwrap_or_default();
+        self.target = match path.chars().next() {
+            Some('/') => path.to_owned(),
+            _ => format!("/{}", path),
+        };
+        self.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Host"));
+        self.headers.insert(0, ("Host".to_owned(), authority.clone()));
+
+        Ok(with_default_port(&authority, default_port))
+    }
+
+    /// The URL of a request rewritten to origin-form and bound for `target_host`, as PAC scripts
+    /// and upstream proxies expect it.
+    pub fn absolute_url(&self, target_host: &str) -> Option<String> {
+        let host = self.header_values("Host").next()?.trim();
+        let authority = match (split_host_port(host).1, split_host_port(target_host).1) {
+            (None, Some(port)) if port != "80" => join_host_port(host, port),
+            _ => host.to_owned(),
+        };
+        Some(format!("http://{}{}", authority, &self.target))
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        persistent_connection(&self.headers, self.minor_version)
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.header_values("Upgrade").next().is_some() && connection_options(&self.headers).iter().any(|option| option == "upgrade")
+    }
+
+    pub fn body_length(&self) -> Result<BodyLength, HeadError> {
+        match declared_body_length(&self.headers)? {
+            Some(BodyLength::UntilClose) => Err(HeadError::Malformed("unsupported request Transfer-Encoding".to_owned())),
+            Some(body_length) => Ok(body_length),
+            None => Ok(BodyLength::Empty),
+        }
+    }
+
+    /// Removes `Expect: 100-continue`, returning whether the client is waiting for a `100 Continue`.
+    /// The body is always sent upstream before a response is read, so dagproxy answers it itself.
+    pub fn take_expect_continue(&mut self) -> bool {
+        let is_continue = |value: &str| value.trim().eq_ignore_ascii_case("100-continue");
+        let expects_continue = self.minor_version >= 1 && self.header_values("Expect").any(is_continue);
+        self.headers.retain(|(name, value)| !(name.eq_ignore_ascii_case("Expect") && is_continue(value)));
+        expects_continue
+    }
+
+    pub fn strip_hop_by_hop(&mut self) {
+        let keep_upgrade = self.is_upgrade();
+        strip_hop_by_hop(&mut self.headers, keep_upgrade);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.{}\r\n", &self.method, &self.target, self.minor_version);
+        write_headers(&mut head, &self.headers);
+        head.into_bytes()
+    }
+}
+
+pub(crate) struct ProxyResponse {
+    pub status: u16,
+    pub reason: String,
+    pub minor_version: u8,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ProxyResponse {
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        header_values(&self.headers, name)
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.header_values("Content-Length")
+            .next()
+            .and_then(|value| value.trim().parse().ok())
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        let connection_close = self
+            .header_values("Connection")
+            .chain(self.header_values("Proxy-Connection"))
+            .any(|value| value.eq_ignore_ascii_case("close"));
+        let chunked = self
+            .header_values("Transfer-Encoding")
+            .any(|value| value.to_ascii_lowercase().contains("chunked"));
+
+        !connection_close && !chunked && self.content_length().is_some()
+    }
+
+    /// All challenges offered across the `Proxy-Authenticate` headers.
+    pub fn auth_challenges(&self) -> Vec<AuthChallenge> {
+        parse_challenges(self.header_values("Proxy-Authenticate"))
+    }
+
+    /// Whether the server keeps the connection open once the body of this response is read.
+    pub fn persistent(&self) -> bool {
+        persistent_connection(&self.headers, self.minor_version)
+    }
+
+    pub fn body_length(&self, request_method: &str) -> Result<BodyLength, anyhow::Error> {
+        if request_method.eq_ignore_ascii_case("HEAD") || (100..200).contains(&self.status) || self.status == 204 || self.status == 304 {
+            return Ok(BodyLength::Empty);
+        }
+        Ok(declared_body_length(&self.headers)?.unwrap_or(BodyLength::UntilClose))
+    }
+
+    pub fn strip_hop_by_hop(&mut self) {
+        strip_hop_by_hop(&mut self.headers, self.status == 101);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, &self.reason);
+        write_headers(&mut head, &self.headers);
+        head.into_bytes()
+    }
+
+    pub fn parse(head: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        if response.parse(head)?.is_partial() {
+            return Err(anyhow!("Incomplete response head"));
+        }
+
+        Ok(ProxyResponse {
+            status: response.code.ok_or_else(|| anyhow!("Response has no status code"))?,
+            reason: response.reason.unwrap_or_default().to_owned(),
+            minor_version: response.version.unwrap_or(1),
+            headers: response
+                .headers
+                .iter()
+                .map(|header| (header.name.to_owned(), String::from_utf8_lossy(header.value).into_owned()))
+                .collect(),
+        })
+    }
+}
+
+/// Reads a response head from the proxy without consuming anything past the empty line,
+/// so the bytes that follow a `200` stay in the socket for the tunnel.
+pub(crate) async fn read_response_head(stream: &mut TcpStream) -> Result<ProxyResponse, anyhow::Error> {
+    let mut head = Vec::new();
+    let mut peek_buffer = [0; 2048];
+
+    loop {
+        let bytes_peeked = stream.peek(&mut peek_buffer).await?;
+        if bytes_peeked == 0 {
+            return Err(anyhow!("Proxy closed connection"));
+        }
+
+        let already_read = head.len();
+        head.extend_from_slice(&peek_buffer[..bytes_peeked]);
+
+        let search_from = already_read.saturating_sub(3);
+        let head_end = head[search_from..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| search_from + position + 4);
+
+        let bytes_to_consume = match head_end {
+            Some(end) => {
+                head.truncate(end);
+                end - already_read
+            }
+            None => bytes_peeked,
+        };
+
+        let mut consumed = vec![0; bytes_to_consume];
+        stream.read_exact(&mut consumed).await?;
+
+        if head_end.is_some() {
+            break;
+        }
+
+        if head.len() > MAX_RESPONSE_HEAD_SIZE {
+            return Err(anyhow!("Proxy response head exceeds {} bytes", MAX_RESPONSE_HEAD_SIZE));
+        }
+    }
+
+    ProxyResponse::parse(&head)
+}
+
+pub(crate) const MAX_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 128;
+const MAX_CHUNK_LINE_SIZE: u64 = 4096;
+
+/// Reads a message head up to and including its empty line from a buffered stream.
+/// Returns `None` when the peer closed the connection before sending anything.
+pub(crate) async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R, max_size: usize) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let mut head = Vec::new();
+
+    loop {
+        let line_limit = (max_size + 1 - head.len()) as u64;
+        let bytes_read = (&mut *reader).take(line_limit).read_until(b'\n', &mut head).await?;
+        if bytes_read == 0 {
+            return match head.is_empty() {
+                true => Ok(None),
+                false => Err(HeadError::Malformed("connection closed in the middle of the head".to_owned()).into()),
+            };
+        }
+        if head.len() > max_size {
+            return Err(HeadError::TooLarge(max_size).into());
+        }
+
+        if head == b"\r\n" || head == b"\n" {
+            // Empty lines before the start line are tolerated (RFC 9112 section 2.2).
+            head.clear();
+        } else if head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n") {
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// Copies one message body from `reader` to `writer`, keeping its framing.
+pub(crate) async fn copy_body<R, W>(reader: &mut R, writer: &mut W, body_length: BodyLength) -> Result<(), anyhow::Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match body_length {
+        BodyLength::Empty => {}
+        BodyLength::Fixed(length) => copy_exact(reader, writer, length).await?,
+        BodyLength::UntilClose => {
+            tokio::io::copy_buf(reader, writer).await?;
+        }
+        BodyLength::Chunked => loop {
+            let size_line = copy_line(reader, writer).await?;
+            let size = size_line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16).map_err(|_| anyhow!("Invalid chunk size: {}", size))?;
+
+            if size == 0 {
+                // Trailer section, terminated by an empty line.
+                while copy_line(reader, writer).await?.trim() != "" {}
+                break;
+            }
+
+            copy_exact(reader, writer, size).await?;
+            if copy_line(reader, writer).await?.trim() != "" {
+                return Err(anyhow!("Missing CRLF after chunk data"));
+            }
+        },
+    }
+
+    writer.flush().await?;
+    Ok(())
+}
 
-pub(crate) async fn connect_to_proxy(proxy_host: &str, target_host: &str) -> Result<TcpStream, anyhow::Error> {
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, length: u64) -> Result<(), anyhow::Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy_buf(&mut (&mut *reader).take(length), writer).await?;
+    if copied < length {
+        return Err(anyhow!("Connection closed after {} of {} body bytes", copied, length));
+    }
+    Ok(())
+}
 
-    let mut proxy_stream = connect_with_retry(proxy_host).await?;
-    proxy_stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", &target_host, &target_host).as_bytes()).await?;
-    proxy_stream.flush().await?;
+async fn copy_line<R, W>(reader: &mut R, writer: &mut W) -> Result<String, anyhow::Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_CHUNK_LINE_SIZE).read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("Truncated chunked body"));
+    }
+    writer.write_all(&line).await?;
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
 
-    let mut read_buffer = [0; 2048];
+async fn discard_body(stream: &mut TcpStream, response: &ProxyResponse) -> Result<(), anyhow::Error> {
+    let mut remaining = response.content_length().unwrap_or(0);
+    let mut discard_buffer = [0; 2048];
+    while remaining > 0 {
+        let to_read = remaining.min(discard_buffer.len());
+        stream.read_exact(&mut discard_buffer[..to_read]).await?;
+        remaining -= to_read;
+    }
+    Ok(())
+}
 
-    let bytes_read = proxy_stream.read(&mut read_buffer).await?;
-    let data = &read_buffer[..bytes_read];
+/// A request sent to an upstream proxy: a `CONNECT`, or a plain HTTP request in absolute-form
+/// whose body is sent again on each leg of the authentication handshake.
+pub(crate) enum ProxyRequest<'a> {
+    Connect(&'a str),
+    Forward { head: &'a RequestHead, body: &'a [u8] },
+}
 
+impl ProxyRequest<'_> {
+    fn method_and_uri(&self) -> (&str, &str) {
+        match self {
+            ProxyRequest::Connect(target_host) => ("CONNECT", target_host),
+            ProxyRequest::Forward { head, .. } => (&head.method, &head.target),
+        }
+    }
 
-    let result = if data.starts_with(b"HTTP/1.1 407") {
-        println!("🤝 Received proxy 407, negotiating Kerberos");
-        drop(proxy_stream);
-        negotiate_with_krb5(&proxy_host).await?;
-        
-        println!("🤝 Proxy negotiate successfull");
+    /// Whether the request may be sent again after `error`: the proxy refused it with a 407, or gave
+    /// no answer at all to a request that does no harm twice, a `CONNECT` or one without a body.
+    fn may_resend(&self, error: &anyhow::Error) -> bool {
+        if let Some(UpstreamError::ProxyAuthentication { .. }) = error.downcast_ref::<UpstreamError>() {
+            return true;
+        }
+        error.downcast_ref::<Unanswered>().is_some()
+            && match self {
+                ProxyRequest::Connect(_) => true,
+                ProxyRequest::Forward { body, .. } => body.is_empty(),
+            }
+    }
 
-        let mut proxy_stream = connect_with_retry(proxy_host).await?;
-        proxy_stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", &target_host, &target_host).as_bytes()).await?;
-        proxy_stream.flush().await?;
+    /// Whether the proxy let the request through: a tunnel needs a 2xx, while a forwarded request
+    /// gets the origin's response whatever its status.
+    fn passed(&self, status: u16) -> bool {
+        match self {
+            ProxyRequest::Connect(_) => (200..300).contains(&status),
+            ProxyRequest::Forward { .. } => status != 407,
+        }
+    }
 
-        Ok(proxy_stream)
+    async fn send(&self, stream: &mut TcpStream, proxy_authorization: Option<&str>) -> Result<ProxyResponse, anyhow::Error> {
+        let authorization_header = proxy_authorization
+            .map(|authorization| format!("Proxy-Authorization: {}\r\n", authorization))
+            .unwrap_or_default();
 
-    } else if data.starts_with(b"HTTP/1.1 2") {
-        Ok(proxy_stream)
+        let message = match self {
+            ProxyRequest::Connect(target_host) => {
+                format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Connection: Keep-Alive\r\n{}\r\n", &target_host, &target_host, &authorization_header).into_bytes()
+            }
+            ProxyRequest::Forward { head, body } => {
+                let mut head = (*head).clone();
+                head.headers.push(("Proxy-Connection".to_owned(), "Keep-Alive".to_owned()));
+                if let Some(authorization) = proxy_authorization {
+                    head.headers.push(("Proxy-Authorization".to_owned(), authorization.to_owned()));
+                }
+                [head.to_bytes(), body.to_vec()].concat()
+            }
+        };
+
+        let mut first_byte = [0; 1];
+        let answered = async {
+            stream.write_all(&message).await?;
+            stream.flush().await?;
+            stream.peek(&mut first_byte).await
+        };
+        match answered.await {
+            Ok(0) => return Err(anyhow!("Proxy closed connection").context(Unanswered)),
+            Ok(_) => {}
+            Err(e) => return Err(anyhow::Error::from(e).context(Unanswered)),
+        }
+
+        read_response_head(stream).await
+    }
+}
+
+/// Prepares the stream for another leg of the handshake after a 407,
+/// reconnecting when the proxy did not keep the connection alive.
+async fn reuse_or_reconnect<C, R>(stream: TcpStream, response: &ProxyResponse, reconnect: &C) -> Result<TcpStream, anyhow::Error>
+where
+    C: Fn() -> R,
+    R: Future<Output = Result<TcpStream, io::Error>>,
+{
+    if response.keep_alive() {
+        let mut stream = stream;
+        discard_body(&mut stream, response).await?;
+        Ok(stream)
+    } else {
+        drop(stream);
+        Ok(reconnect().await?)
+    }
+}
+
+pub(crate) async fn connect_to_proxy(pool: &UpstreamPool, proxy_host: &str, target_host: &str, auth: &[ProxyAuth], spn: &ProxySpn) -> Result<TcpStream, anyhow::Error> {
+    let (proxy_stream, _) = send_to_proxy(pool, proxy_host, &ProxyRequest::Connect(target_host), auth, spn).await?;
+    Ok(proxy_stream)
+}
+
+/// Sends a plain HTTP request in absolute-form and its body to the proxy. Returns the connection,
+/// positioned at the start of the response body, along with the response head.
+pub(crate) async fn forward_to_proxy(
+    pool: &UpstreamPool,
+    proxy_host: &str,
+    head: &RequestHead,
+    body: &[u8],
+    auth: &[ProxyAuth],
+    spn: &ProxySpn,
+) -> Result<(TcpStream, ProxyResponse), anyhow::Error> {
+    send_to_proxy(pool, proxy_host, &ProxyRequest::Forward { head, body }, auth, spn).await
+}
+
+/// Opens a tunnel over `proxy_stream`, a connection of its own rather than a pooled one, as health
+/// checks need. A proxy that closes it during the handshake is connected to again within `timeout`.
+pub(crate) async fn connect_over(
+    proxy_stream: TcpStream,
+    proxy_host: &str,
+    target_host: &str,
+    auth: &[ProxyAuth],
+    spn: &ProxySpn,
+    timeout: Duration,
+) -> Result<TcpStream, anyhow::Error> {
+    let proxy_spn = negotiate_spn(proxy_host, auth, spn).await;
+    let reconnect = || connect_once(proxy_host, timeout);
+    let (proxy_stream, _) = send_to_proxy_with(proxy_stream, &ProxyRequest::Connect(target_host), &reconnect, |challenges| {
+        select_authenticators(auth, challenges, &proxy_spn, "CONNECT", target_host)
+    }, |_| {})
+    .await?;
+    Ok(proxy_stream)
+}
+
+/// The SPN to ask a ticket for, when Negotiate is among the configured schemes.
+async fn negotiate_spn(proxy_host: &str, auth: &[ProxyAuth], spn: &ProxySpn) -> String {
+    if auth.iter().any(|proxy_auth| matches!(proxy_auth, ProxyAuth::Negotiate(_))) {
+        proxy_spn(proxy_host, spn).await
     } else {
-        if bytes_read != 0 {
-            Err(anyhow!("Received Error from proxy: {}", String::from_utf8_lossy(&data) ))
-        } else {
-            Err(anyhow!("Proxy closed connection for target_host: {}", &target_host))
+        String::new()
+    }
+}
+
+async fn send_to_proxy(
+    pool: &UpstreamPool,
+    proxy_host: &str,
+    request: &ProxyRequest<'_>,
+    auth: &[ProxyAuth],
+    spn: &ProxySpn,
+) -> Result<(TcpStream, ProxyResponse), anyhow::Error> {
+    let proxy_spn = negotiate_spn(proxy_host, auth, spn).await;
+    let (method, uri) = request.method_and_uri();
+
+    // Skip the 407 round trip when we already know which scheme this proxy wants.
+    if let Some(scheme) = pool.preferred_scheme(proxy_host) {
+        let known_challenge = [AuthChallenge { scheme: scheme.to_owned(), params: String::new() }];
+        let authenticator = select_authenticators(auth, &known_challenge, &proxy_spn, method, uri).into_iter().next();
+
+        if let Some(authenticator) = authenticator {
+            let connection = pool.checkout(proxy_host).await?;
+            match authenticate_preemptively(connection, authenticator, request).await {
+                Ok(exchange) => return Ok(exchange),
+                // Any other answer reached the origin or is the proxy's final word on the request.
+                Err(e) if !request.may_resend(&e) => return Err(e),
+                Err(e) => {
+                    eprintln!("🤝 Preemptive proxy {} authentication failed, renegotiating: {:#}", scheme, e);
+                    if let Some(UpstreamError::ProxyAuthentication { .. }) = e.downcast_ref::<UpstreamError>() {
+                        pool.forget_scheme(proxy_host);
+                    }
+                }
+            }
+        }
+    }
+
+    let reconnect = || async move { pool.checkout(proxy_host).await.map(|connection| connection.stream) };
+    let proxy_stream = reconnect().await?;
+    send_to_proxy_with(proxy_stream, request, &reconnect, |challenges| {
+        select_authenticators(auth, challenges, &proxy_spn, method, uri)
+    }, |scheme| pool.remember_scheme(proxy_host, scheme, auth, &proxy_spn))
+    .await
+}
+
+/// Sends the request with the credentials its connection still needs: none when the proxy already
+/// accepted a connection-based scheme on it, those computed when it was opened if any, otherwise
+/// the first leg of `authenticator`.
+async fn authenticate_preemptively(
+    connection: PooledConnection,
+    mut authenticator: Box<dyn ProxyAuthenticator>,
+    request: &ProxyRequest<'_>,
+) -> Result<(TcpStream, ProxyResponse), anyhow::Error> {
+    let (mut authenticator, authorization) = match connection.auth {
+        ConnectionAuth::Prepared(prepared, authorization) if prepared.scheme() == authenticator.scheme() => (prepared, authorization),
+        ConnectionAuth::Authenticated if matches!(authenticator.scheme(), "NTLM" | "Negotiate") => (authenticator, None),
+        _ => {
+            let authorization = authenticator.respond(None).map_err(|e| authentication_failed(None, format!("{:#}", e)))?;
+            (authenticator, authorization)
         }
     };
+    authenticate_request(connection.stream, authenticator.as_mut(), authorization, request).await
+}
+
+/// Sends the request over `proxy_stream` and, on a 407, lets `select` pick authenticators from the
+/// proxy's challenges, trying each of them in turn until one gets the request through. `reconnect`
+/// opens another connection when the proxy closes one, and `remember` is given the scheme that got
+/// through, unless it cannot be sent preemptively.
+async fn send_to_proxy_with<C, R, F, M>(
+    mut proxy_stream: TcpStream,
+    request: &ProxyRequest<'_>,
+    reconnect: &C,
+    select: F,
+    remember: M,
+) -> Result<(TcpStream, ProxyResponse), anyhow::Error>
+where
+    C: Fn() -> R,
+    R: Future<Output = Result<TcpStream, io::Error>>,
+    F: FnOnce(&[AuthChallenge]) -> Vec<Box<dyn ProxyAuthenticator>>,
+    M: FnOnce(&'static str),
+{
+    let response = request.send(&mut proxy_stream, None).await?;
+
+    match response.status {
+        407 => {
+            let challenges = response.auth_challenges();
+            let authenticators = select(&challenges);
+            let mut proxy_stream = Some(reuse_or_reconnect(proxy_stream, &response, reconnect).await?);
+            let mut last_error = anyhow!(
+                "Proxy requires authentication but none of the configured schemes are offered: {}",
+                challenges.iter().map(|challenge| challenge.scheme.as_str()).collect::<Vec<_>>().join(", ")
+            );
+
+            for mut authenticator in authenticators {
+                let scheme = authenticator.scheme();
+                println!("🤝 Received proxy 407, negotiating {}", scheme);
+
+                let stream = match proxy_stream.take() {
+                    Some(stream) => stream,
+                    None => reconnect().await?,
+                };
+                let initial_challenge = find_challenge(&challenges, scheme);
+                let authenticated = async {
+                    let authorization = authenticator
+                        .respond(initial_challenge.as_deref())
+                        .map_err(|e| authentication_failed(Some(&response), format!("{:#}", e)))?;
+                    authenticate_request(stream, authenticator.as_mut(), authorization, request).await
+                };
+
+                match authenticated.await {
+                    Ok(exchange) => {
+                        println!("🤝 Proxy {} negotiate successfull", scheme);
+                        // Digest needs a fresh nonce from a 407, so it cannot be sent preemptively.
+                        if scheme != "Digest" {
+                            remember(scheme);
+                        }
+                        return Ok(exchange);
+                    }
+                    Err(e) if !request.may_resend(&e) => return Err(e),
+                    Err(e) => {
+                        eprintln!("🤝 Proxy {} negotiate failed: {:#}", scheme, e);
+                        last_error = e;
+                    }
+                }
+            }
+
+            let reason = match last_error.downcast_ref::<UpstreamError>() {
+                Some(UpstreamError::ProxyAuthentication { reason, .. }) => reason.clone(),
+                _ => format!("{:#}", last_error),
+            };
+            Err(authentication_failed(Some(&response), reason))
+        }
+        status if request.passed(status) => Ok((proxy_stream, response)),
+        status => Err(UpstreamError::ProxyStatus(status).into()),
+    }
+}
+
+fn find_challenge(challenges: &[AuthChallenge], scheme: &str) -> Option<String> {
+    challenges
+        .iter()
+        .find(|challenge| challenge.scheme.eq_ignore_ascii_case(scheme) && !challenge.params.is_empty())
+        .map(|challenge| challenge.params.clone())
+}
+
+/// Drives one authenticator over as many legs as it needs on the same keep-alive connection,
+/// starting with `authorization`.
+async fn authenticate_request(
+    mut proxy_stream: TcpStream,
+    authenticator: &mut dyn ProxyAuthenticator,
+    mut authorization: Option<String>,
+    request: &ProxyRequest<'_>,
+) -> Result<(TcpStream, ProxyResponse), anyhow::Error> {
+    loop {
+        let response = request.send(&mut proxy_stream, authorization.as_deref()).await?;
+        let challenge = find_challenge(&response.auth_challenges(), authenticator.scheme());
+
+        match response.status {
+            407 => {
+                let next_leg = async {
+                    if !response.keep_alive() {
+                        return Err(anyhow!("Proxy closed connection in the middle of the {} handshake", authenticator.scheme()));
+                    }
+                    discard_body(&mut proxy_stream, &response).await?;
+                    authenticator.respond(challenge.as_deref())
+                };
+                authorization = next_leg.await.map_err(|e| authentication_failed(Some(&response), format!("{:#}", e)))?;
+            }
+            status if request.passed(status) => {
+                authenticator.finish(challenge.as_deref())?;
+                return Ok((proxy_stream, response));
+            }
+            status => return Err(UpstreamError::ProxyStatus(status).into()),
+        }
+    }
+}
+
+/// A 407 that could not be answered, keeping the challenges of its `response` for the client.
+fn authentication_failed(response: Option<&ProxyResponse>, reason: String) -> anyhow::Error {
+    let challenges = response.map(|response| response.header_values("Proxy-Authenticate").map(str::to_owned).collect());
+    UpstreamError::ProxyAuthentication { challenges: challenges.unwrap_or_default(), reason }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PoolConfig, ProxyAuth, ProxyCredentials, ProxySpn, Secret};
+    use crate::kerberos_cache::TicketStatus;
+    use crate::http::{connect_to_proxy, send_to_proxy_with, copy_body, ProxyRequest, join_host_port, split_host_port, BodyLength, HeadAccumulator, HeadError, RequestHead, UpstreamError, upstream_error_response};
+    use crate::upstream_pool::{ConnectionAuth, UpstreamPool};
+    use crate::proxy_auth::ProxyAuthenticator;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    struct FakeAuthenticator;
+
+    impl ProxyAuthenticator for FakeAuthenticator {
+        fn scheme(&self) -> &'static str {
+            "Fake"
+        }
+
+        fn respond(&mut self, challenge: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+            match challenge {
+                None => Ok(Some("Fake first-leg".to_owned())),
+                Some(challenge) => Ok(Some(format!("Fake answer-to-{}", challenge))),
+            }
+        }
+    }
+
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_multi_leg_authentication_on_same_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_host = listener.local_addr().unwrap().to_string();
+
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let request = read_request(&mut stream).await;
+            assert!(!request.contains("Proxy-Authorization"));
+            stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Fake\r\nContent-Length: 4\r\n\r\nnope").await.unwrap();
+
+            let request = read_request(&mut stream).await;
+            assert!(request.contains("Proxy-Authorization: Fake first-leg\r\n"));
+            stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Fake challenge\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+
+            let request = read_request(&mut stream).await;
+            assert!(request.contains("Proxy-Authorization: Fake answer-to-challenge\r\n"));
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\ntunnel").await.unwrap();
+        });
+
+        let proxy_stream = TcpStream::connect(&proxy_host).await.unwrap();
+        let reconnect = || TcpStream::connect(&proxy_host);
+        let (mut stream, _) = send_to_proxy_with(proxy_stream, &ProxyRequest::Connect("example.com:443"), &reconnect, |challenges| {
+            assert_eq!(challenges[0].scheme, "Fake");
+            vec![Box::new(FakeAuthenticator) as Box<dyn ProxyAuthenticator>]
+        }, |scheme| assert_eq!(scheme, "Fake"))
+        .await
+        .unwrap();
+
+        let mut tunnel_data = [0; 6];
+        stream.read_exact(&mut tunnel_data).await.unwrap();
+        assert_eq!(&tunnel_data, b"tunnel");
+
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_preemptive_request_is_not_sent_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_host = listener.local_addr().unwrap().to_string();
+
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_request(&mut stream).await;
+            assert!(request.contains("Proxy-Authorization: Basic "));
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+
+            let mut next = [0; 1];
+            let same_connection = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut next)).await;
+            assert!(matches!(same_connection, Err(_) | Ok(Ok(0))));
+            assert!(tokio::time::timeout(Duration::from_millis(300), listener.accept()).await.is_err());
+        });
+
+        let pool = UpstreamPool::new(PoolConfig { prewarm: 0, ..PoolConfig::default() });
+        let auth = [ProxyAuth::Basic(ProxyCredentials {
+            username: "user".to_owned(),
+            domain: None,
+            password: Secret::Plain("password".to_owned()),
+        })];
+        pool.remember_scheme(&proxy_host, "Basic", &auth, "");
 
-    result
-}
\ No newline at end of file
+        let error = connect_to_proxy(&pool, &proxy_host, "example.com:443", &auth, &ProxySpn::default()).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<UpstreamError>(), Some(UpstreamError::ProxyStatus(403))));
+        assert_eq!(pool.preferred_scheme(&proxy_host), Some("Basic"));
+
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_request_is_resent_with_its_body_and_pooled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_host = listener.local_addr().unwrap().to_string();
+
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut body = [0; 5];
+
+            let request = read_request(&mut stream).await;
+            assert!(request.starts_with("POST http://example.com/form HTTP/1.1\r\n"));
+            stream.read_exact(&mut body).await.unwrap();
+            stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Fake\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+
+            let request = read_request(&mut stream).await;
+            assert!(request.contains("Proxy-Authorization: Fake first-leg\r\n"));
+            stream.read_exact(&mut body).await.unwrap();
+            assert_eq!(&body, b"hello");
+            stream.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            stream
+        });
+
+        let pool = UpstreamPool::new(PoolConfig { prewarm: 0, ..PoolConfig::default() });
+        let head = RequestHead::parse(b"POST http://example.com/form HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\n").unwrap();
+        let request = ProxyRequest::Forward { head: &head, body: b"hello" };
+        let reconnect = || async { pool.checkout(&proxy_host).await.map(|connection| connection.stream) };
+        let (mut stream, response) = send_to_proxy_with(reconnect().await.unwrap(), &request, &reconnect, |_| {
+            vec![Box::new(FakeAuthenticator) as Box<dyn ProxyAuthenticator>]
+        }, |_| {})
+        .await
+        .unwrap();
+        assert_eq!(response.status, 201);
+
+        let mut response_body = [0; 2];
+        stream.read_exact(&mut response_body).await.unwrap();
+        pool.checkin(&proxy_host, stream);
+        assert!(matches!(pool.checkout(&proxy_host).await.unwrap().auth, ConnectionAuth::Authenticated));
+
+        proxy.await.unwrap();
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("proxy.example.com:8080"), ("proxy.example.com", Some("8080")));
+        assert_eq!(split_host_port("[2001:db8::1]:3128"), ("2001:db8::1", Some("3128")));
+        assert_eq!(split_host_port("2001:db8::1"), ("2001:db8::1", None));
+        assert_eq!(join_host_port("2001:db8::1", 3128), "[2001:db8::1]:3128");
+    }
+
+    #[test]
+    fn test_rewrite_to_origin_form() {
+        let mut request = RequestHead::parse(
+            b"GET http://example.com:8080/path?query HTTP/1.1\r\nHost: ignored\r\nProxy-Connection: keep-alive\r\nConnection: X-Drop\r\nX-Drop: 1\r\nProxy-Authorization: Basic Zm9v\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(request.rewrite_to_origin_form(None).unwrap(), "example.com:8080");
+        assert_eq!(request.absolute_url("example.com:8080").as_deref(), Some("http://example.com:8080/path?query"));
+        request.strip_hop_by_hop();
+        assert_eq!(
+            String::from_utf8(request.to_bytes()).unwrap(),
+            "GET /path?query HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n"
+        );
+
+        let mut request = RequestHead::parse(b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n").unwrap();
+        assert_eq!(request.rewrite_to_origin_form(None).unwrap(), "example.org:80");
+    }
+
+    #[test]
+    fn test_redirected_request_goes_to_original_destination() {
+        let original_destination = Some("10.0.0.5:8080".parse().unwrap());
+
+        let mut request = RequestHead::parse(b"GET /app HTTP/1.1\r\nHost: intranet.example.com\r\n\r\n").unwrap();
+        let target_host = request.rewrite_to_origin_form(original_destination).unwrap();
+        assert_eq!(target_host, "intranet.example.com:8080");
+        assert_eq!(request.absolute_url(&target_host).as_deref(), Some("http://intranet.example.com:8080/app"));
+
+        let mut request = RequestHead::parse(b"GET /app HTTP/1.0\r\n\r\n").unwrap();
+        assert!(request.rewrite_to_origin_form(None).is_err());
+        assert_eq!(request.rewrite_to_origin_form(original_destination).unwrap(), "10.0.0.5:8080");
+        assert_eq!(request.header_values("Host").next(), Some("10.0.0.5:8080"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_chunked_body_stops_at_last_chunk() {
+        let mut reader: &[u8] = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET /next HTTP/1.1\r\n";
+        let mut body = Vec::new();
+
+        copy_body(&mut reader, &mut body, BodyLength::Chunked).await.unwrap();
+
+        assert_eq!(body, b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\n");
+        assert_eq!(reader, b"GET /next HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_head_accumulator_waits_for_complete_head() {
+        let mut accumulator = HeadAccumulator::new(64);
+
+        assert!(accumulator.push(b"CONNECT example.com:443 HTTP/1.1\r\nHo").unwrap().is_none());
+        assert!(accumulator.push(b"st: example.com:443\r\n\r").unwrap().is_none());
+        let head = accumulator.push(b"\n\x16\x03\x01").unwrap().unwrap();
+
+        assert!(head.ends_with(b"Host: example.com:443\r\n\r\n"));
+        assert_eq!(accumulator.take_remaining(), b"\x16\x03\x01");
+
+        let mut accumulator = HeadAccumulator::new(16);
+        assert!(matches!(accumulator.push(b"GET / HTTP/1.1\r\nCookie: a=b\r\n"), Err(HeadError::TooLarge(16))));
+    }
+
+    #[test]
+    fn test_expect_continue_is_answered_locally() {
+        let mut request = RequestHead::parse(b"PUT /upload HTTP/1.1\r\nHost: example.com\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert!(request.take_expect_continue());
+        assert!(!String::from_utf8(request.to_bytes()).unwrap().contains("Expect"));
+
+        let mut request = RequestHead::parse(b"PUT /upload HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert!(!request.take_expect_continue());
+    }
+
+    #[test]
+    fn test_too_many_headers() {
+        let head = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(200));
+        assert!(matches!(RequestHead::parse(head.as_bytes()), Err(HeadError::TooManyHeaders(128))));
+    }
+
+    #[test]
+    fn test_upstream_error_response_names_route() {
+        let error = UpstreamError::ProxyAuthentication {
+            challenges: vec!["Negotiate".to_owned()],
+            reason: "no ticket".to_owned(),
+        };
+        let ticket_status = TicketStatus::Unavailable("no TGT".to_owned());
+        let response = String::from_utf8(upstream_error_response(&error.into(), "proxy proxy.example.com:8080", "example.com:443", &ticket_status)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Negotiate\r\n"));
+        assert!(response.contains("Route: proxy proxy.example.com:8080\n"));
+        assert!(response.contains("Kerberos: No Kerberos ticket available: no TGT\n"));
+
+        let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        let response = String::from_utf8(upstream_error_response(&error.into(), "direct", "example.com:80", &ticket_status)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+    }
+}
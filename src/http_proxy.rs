@@ -1,39 +1,80 @@
-use crate::config::ProxyConfig;
+use crate::NoProxyValue;
+use crate::config::{ProxyAuth, ProxyConfig, ProxyKind, ProxySpn, SocksCredentials, UpstreamPolicy, UpstreamProxy};
+use crate::mitm::Interceptor;
+use crate::pac::{self, PacResolver, PacRoute};
 use crate::http::{
-    RequestType, SUCCESS_CONNECT_RESPONSE, connect_to_proxy, connect_with_retry,
-    parse_host_from_request,
+    BodyLength, HeadAccumulator, HeadError, MAX_RESPONSE_HEAD_SIZE, ProxyResponse, RequestHead, RequestType, UpstreamError,
+    CONTINUE_RESPONSE, SUCCESS_CONNECT_RESPONSE, connect_to_proxy, connect_with_retry, content_response, copy_body, error_response,
+    forward_to_proxy, join_host_port, read_head, request_type, split_host_port, upstream_error_response,
 };
+use crate::kerberos_cache::TicketWatchHandle;
 use crate::network_watcher::NetworkWatchHandle;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::socks::{self, SOCKS4_VERSION, SOCKS5_VERSION, connect_via_socks4a, connect_via_socks5};
+use crate::tls::{self, CONTENT_TYPE_HANDSHAKE};
+use crate::upstream_pool::{UpstreamLease, UpstreamPool};
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 
+const CLIENT_FIRST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Largest request body sent to an HTTP proxy along with its plain request, as it is kept to be sent
+/// again after a 407. Requests with larger or chunked bodies go through a tunnel instead.
+const MAX_FORWARDED_BODY: u64 = 1024 * 1024;
+
+/// Settings shared by every connection a listener accepts.
+#[derive(Clone)]
+pub(crate) struct TunnelSettings {
+    pub max_header_size: usize,
+    pub socks_credentials: Option<SocksCredentials>,
+    pub interceptor: Option<Arc<Interceptor>>,
+    pub pac: PacResolver,
+    /// Connect directly when every upstream proxy of the subnet is down.
+    pub direct_fallback: bool,
+    pub tickets: TicketWatchHandle,
+}
+
+/// Listener for proxy clients. HTTP, SOCKS4/4a, SOCKS5 and raw TLS are told apart
+/// by their first bytes, so a single port serves them all.
 pub struct HttpProxy {
     network_watcher: NetworkWatchHandle,
+    upstream_pool: UpstreamPool,
+    settings: TunnelSettings,
 }
 
 impl HttpProxy {
     pub(crate) fn new(
         network_watcher: NetworkWatchHandle,
+        upstream_pool: UpstreamPool,
+        settings: TunnelSettings,
     ) -> Self {
         Self {
             network_watcher,
+            upstream_pool,
+            settings,
         }
     }
 
     pub async fn start(&mut self, host: String, port: u32) -> Result<(), anyhow::Error> {
         let listener = TcpListener::bind(format!("{}:{}", &host, &port)).await?;
 
-        println!("👂 HTTP Proxy listening on {}:{}", &host, &port);
+        println!("👂 Proxy listening on {}:{} (HTTP, SOCKS4, SOCKS5, TLS)", &host, &port);
 
         loop {
             match listener.accept().await {
                 Ok((source_socket, _)) => {
                     let network_watcher = self.network_watcher.clone();
+                    let upstream_pool = self.upstream_pool.clone();
+                    let settings = self.settings.clone();
 
                     let _ = tokio::spawn(async move {
                         let mut proxy_tunnel = ProxyTunnel::new(
                             source_socket,
                             network_watcher,
+                            upstream_pool,
+                            settings,
                         );
                         proxy_tunnel.start().await;
                     });
@@ -49,10 +90,18 @@ impl HttpProxy {
     }
 }
 
-struct ProxyTunnel {
+pub(crate) struct ProxyTunnel {
     source_socket: TcpStream,
     network_watcher: NetworkWatchHandle,
+    upstream_pool: UpstreamPool,
+    settings: TunnelSettings,
+    original_destination: Option<SocketAddr>,
+    request_head: HeadAccumulator,
     dest_socket: Option<TcpStream>,
+    /// Keeps the upstream proxy of `dest_socket` counted as busy.
+    upstream_lease: Option<UpstreamLease>,
+    /// Address the client asked for when the tunnel is routed by its TLS server name instead.
+    requested_address: Option<String>,
     state: ConnectionState,
 }
 
@@ -60,19 +109,38 @@ impl ProxyTunnel {
     pub fn new(
         source_socket: TcpStream,
         network_watcher: NetworkWatchHandle,
+        upstream_pool: UpstreamPool,
+        settings: TunnelSettings,
     ) -> Self {
         Self {
             source_socket,
             network_watcher,
+            upstream_pool,
+            request_head: HeadAccumulator::new(settings.max_header_size),
+            settings,
+            original_destination: None,
             dest_socket: None,
+            upstream_lease: None,
+            requested_address: None,
             state: ConnectionState::Initializing,
         }
     }
 
+    /// Marks the connection as redirected by the firewall from `original_destination`.
+    pub fn transparent(mut self, original_destination: SocketAddr) -> Self {
+        self.original_destination = Some(original_destination);
+        self
+    }
+
     pub async fn start(&mut self) {
+        if let Err(e) = self.sniff_protocol().await {
+            eprintln!("Error accepting client: {}", e);
+            return;
+        }
+
         let mut network_update_receiver = self.network_watcher.subscribe();
 
-        loop {
+        while self.state != ConnectionState::Closed {
             let mut source_read_buffer = [0; 2048];
             let mut dest_read_buffer = [0; 2048];
 
@@ -136,6 +204,7 @@ impl ProxyTunnel {
                 self.initialize(data).await?;
                 Ok(())
             }
+            ConnectionState::Closed => Ok(()),
             ConnectionState::Forwarding(_) => {
                 self.dest_socket
                     .as_mut()
@@ -147,63 +216,602 @@ impl ProxyTunnel {
         }
     }
 
+    /// Waits for the whole request head before routing, answering `431`/`400` when it cannot be read.
     async fn initialize(&mut self, data: &[u8]) -> Result<(), anyhow::Error> {
-        let (request_type, target_host) = parse_host_from_request(&data)?;
+        let head = match self.request_head.push(data) {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(self.answer_and_close(&e.response(), e.into()).await),
+        };
+        let rest = self.request_head.take_remaining();
+
+        let RequestType::Connect = request_type(&head) else {
+            if self.original_destination.is_none()
+                && let Ok(request) = RequestHead::parse(&head)
+                && request.target.starts_with('/')
+            {
+                return self.serve_local(&request).await;
+            }
+            self.forward_requests(&[head, rest].concat()).await?;
+            self.state = ConnectionState::Closed;
+            return Ok(());
+        };
+
+        let target_host = match RequestHead::parse(&head).and_then(|request| request.connect_target()) {
+            Ok(target_host) => target_host,
+            Err(e) => return Err(self.answer_and_close(&e.response(), e.into()).await),
+        };
         let network_type = self.network_watcher.network_type();
-        self.setup_dest_socket(network_type, &target_host).await?;
-        self.state = ConnectionState::Forwarding(target_host);
+        if let Some(interceptor) = self.settings.interceptor.clone()
+            && interceptor.intercepts(&target_host)
+        {
+            return self.intercept(&interceptor, network_type, &target_host, rest).await;
+        }
+        if route_needs_server_name(&network_type, &target_host) {
+            self.source_socket.write_all(SUCCESS_CONNECT_RESPONSE).await?;
+            return self.connect_by_server_name(&target_host, rest).await;
+        }
 
-        match request_type {
-            RequestType::Connect => {
-                self.source_socket
-                    .write_all(SUCCESS_CONNECT_RESPONSE)
-                    .await?;
+        let route = route_name(&network_type, &target_host);
+        if let Err(e) = self.connect(&target_host).await {
+            let response = upstream_error_response(&e, &route, &target_host, &self.settings.tickets.status());
+            return Err(self.answer_and_close(&response, e).await);
+        }
+
+        self.source_socket
+            .write_all(SUCCESS_CONNECT_RESPONSE)
+            .await?;
+        if !rest.is_empty() {
+            self.dest_socket.as_mut().expect("to be here").write_all(&rest).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Answers requests made to dagproxy itself rather than through it, which only serves its PAC
+    /// script (`/proxy.pac`, or `/wpad.dat` for WPAD clients).
+    async fn serve_local(&mut self, request: &RequestHead) -> Result<(), anyhow::Error> {
+        let path = request.target.split('?').next().unwrap_or_default();
+        let (status, response) = match (request.method.as_str(), path) {
+            ("GET" | "HEAD", "/proxy.pac" | "/wpad.dat") => {
+                let script = pac::generate_pac(&self.network_watcher.network_type(), self.source_socket.local_addr()?);
+                (200, content_response("application/x-ns-proxy-autoconfig", &script, request.method == "HEAD"))
             }
-            RequestType::Other => {
-                self.dest_socket
-                    .as_mut()
-                    .expect("to be here")
-                    .write_all(data)
-                    .await?;
+            _ => (404, error_response(404, "Not Found", &[], "dagproxy only serves /proxy.pac and /wpad.dat\n")),
+        };
+        println!("📜 {} {} {}", &request.method, &request.target, status);
+
+        self.source_socket.write_all(&response).await?;
+        self.state = ConnectionState::Closed;
+        Ok(())
+    }
+
+    /// Looks at the first byte the client sent. SOCKS and TLS clients are connected to their target
+    /// right away, HTTP clients are left to [`ProxyTunnel::initialize`]. Redirected connections
+    /// fall back to their original destination when the protocol says nothing about the host.
+    async fn sniff_protocol(&mut self) -> Result<(), anyhow::Error> {
+        let mut first_byte = [0; 1];
+        let peek = self.source_socket.peek(&mut first_byte);
+        let peeked = match self.original_destination {
+            // Server-first protocols (SSH, SMTP...) send nothing until they are greeted.
+            Some(_) => tokio::time::timeout(CLIENT_FIRST_TIMEOUT, peek).await.ok(),
+            None => Some(peek.await),
+        };
+        let first_byte = match peeked.transpose()? {
+            Some(0) => {
+                self.state = ConnectionState::Closed;
+                return Ok(());
             }
+            Some(_) => Some(first_byte[0]),
+            None => None,
+        };
+
+        match (first_byte, self.original_destination) {
+            (Some(CONTENT_TYPE_HANDSHAKE), original_destination) => {
+                let client_hello = tls::read_client_hello(&mut self.source_socket).await?;
+                let target_host = match (tls::server_name(&client_hello), original_destination) {
+                    (Some(server_name), Some(destination)) => join_host_port(&server_name, destination.port()),
+                    (Some(server_name), None) => join_host_port(&server_name, 443),
+                    (None, Some(destination)) => destination.to_string(),
+                    (None, None) => return Err(anyhow::anyhow!("TLS client sent no server name")),
+                };
+
+                self.connect(&target_host).await?;
+                self.dest_socket.as_mut().expect("to be here").write_all(&client_hello).await?;
+                Ok(())
+            }
+            (Some(version @ (SOCKS4_VERSION | SOCKS5_VERSION)), None) => {
+                let credentials = self.settings.socks_credentials.as_ref();
+                let target_host = match version {
+                    SOCKS4_VERSION => socks::socks4_handshake(&mut self.source_socket, credentials).await?,
+                    _ => socks::handshake(&mut self.source_socket, credentials).await?,
+                };
+
+                if route_needs_server_name(&self.network_watcher.network_type(), &target_host) {
+                    self.source_socket.write_all(&socks::connect_reply(version, &Ok(()))).await?;
+                    return self.connect_by_server_name(&target_host, Vec::new()).await;
+                }
+
+                let connected = self.connect(&target_host).await;
+                self.source_socket.write_all(&socks::connect_reply(version, &connected)).await?;
+                connected
+            }
+            // An HTTP method: routed by its Host header like any proxy request.
+            (Some(byte), Some(_)) if byte.is_ascii_uppercase() => Ok(()),
+            (_, Some(destination)) => self.connect(&destination.to_string()).await,
+            _ => Ok(()),
         }
+    }
 
+    /// Routes the tunnel to `target_host` for clients that did not send an HTTP request,
+    /// after which bytes are relayed both ways.
+    async fn connect(&mut self, target_host: &str) -> Result<(), anyhow::Error> {
+        let network_type = self.network_watcher.network_type();
+        self.setup_dest_socket(network_type, target_host).await?;
+        self.state = ConnectionState::Forwarding(target_host.to_owned());
         Ok(())
     }
 
+    /// Connects a tunnel the client asked for by address once its TLS ClientHello names the host,
+    /// so `no_proxy` host names apply. The name only picks the route: a direct connection still goes
+    /// to the address. The client has already been told the tunnel is open, and `pending` is what it sent since.
+    async fn connect_by_server_name(&mut self, target_host: &str, mut pending: Vec<u8>) -> Result<(), anyhow::Error> {
+        let routed_host = loop {
+            match tls::client_hello_length(&pending) {
+                Ok(Some(length)) => {
+                    let routed_host = server_name_host(target_host, &pending[..length]);
+                    if routed_host != target_host {
+                        println!("🔎 {} is {}", target_host, &routed_host);
+                        self.requested_address = Some(target_host.to_owned());
+                    }
+                    break routed_host;
+                }
+                Ok(None) => {}
+                // Not TLS, the address is all there is to route on.
+                Err(_) => break target_host.to_owned(),
+            }
+
+            let mut buffer = [0; 2048];
+            match tokio::time::timeout(CLIENT_FIRST_TIMEOUT, self.source_socket.read(&mut buffer)).await {
+                Ok(Ok(0)) => {
+                    self.state = ConnectionState::Closed;
+                    return Ok(());
+                }
+                Ok(Ok(bytes_read)) => pending.extend_from_slice(&buffer[..bytes_read]),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => break target_host.to_owned(),
+            }
+        };
+
+        self.connect(&routed_host).await?;
+        if !pending.is_empty() {
+            self.dest_socket.as_mut().expect("to be here").write_all(&pending).await?;
+        }
+        Ok(())
+    }
+
+    /// Opens a TLS connection to `target_host` and hands the tunnel over to the interceptor,
+    /// which serves the client's requests itself.
+    async fn intercept(
+        &mut self,
+        interceptor: &Interceptor,
+        network_type: ProxyConfig,
+        target_host: &str,
+        pending: Vec<u8>,
+    ) -> Result<(), anyhow::Error> {
+        let route = route_name(&network_type, target_host);
+        let server = async {
+            let (dest_socket, upstream_lease) = open_dest_socket(&self.upstream_pool, &self.settings, network_type, target_host, None).await?;
+            Ok::<_, anyhow::Error>((interceptor.connect(dest_socket, target_host).await?, upstream_lease))
+        };
+        let (server, _upstream_lease) = match server.await {
+            Ok(server) => server,
+            Err(e) => {
+                let response = upstream_error_response(&e, &route, target_host, &self.settings.tickets.status());
+                return Err(self.answer_and_close(&response, e).await);
+            }
+        };
+
+        self.source_socket.write_all(SUCCESS_CONNECT_RESPONSE).await?;
+        self.state = ConnectionState::Closed;
+        interceptor
+            .intercept(&mut self.source_socket, pending, server, target_host, self.settings.max_header_size)
+            .await
+    }
+
+    /// Sends an error response before the connection is dropped, passing `error` through for logging.
+    async fn answer_and_close(&mut self, response: &[u8], error: anyhow::Error) -> anyhow::Error {
+        if let Err(e) = self.source_socket.write_all(response).await {
+            eprintln!("Error answering client: {}", e);
+        }
+        self.state = ConnectionState::Closed;
+        error
+    }
+
+    /// Forwards plain HTTP requests one at a time, routing each one by its own target so a
+    /// keep-alive client can switch hosts. `initial_data` is what was already read from the client.
+    async fn forward_requests(&mut self, initial_data: &[u8]) -> Result<(), anyhow::Error> {
+        let original_destination = self.original_destination;
+        let (source_read, mut source_write) = self.source_socket.split();
+        let mut source_reader = BufReader::new(Cursor::new(initial_data.to_vec()).chain(source_read));
+        let mut upstream: Option<Upstream> = None;
+
+        loop {
+            let read = read_request(&mut source_reader, self.settings.max_header_size, original_destination).await;
+            let (mut request, target_host, request_body) = match read {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    if let Some(head_error) = e.downcast_ref::<HeadError>() {
+                        source_write.write_all(&head_error.response()).await?;
+                    }
+                    return Err(e);
+                }
+            };
+            let client_keep_alive = request.keep_alive();
+            let upgrade = request.is_upgrade();
+            let request_url = request.absolute_url(&target_host);
+            let expects_continue = request.take_expect_continue() && request_body != BodyLength::Empty;
+            request.strip_hop_by_hop();
+
+            let network_type = self.network_watcher.network_type();
+            let forwarding_proxies = if upgrade {
+                None
+            } else {
+                forwarding_proxies(&self.upstream_pool, &self.settings, &network_type, &target_host, request_body)
+            };
+            let exchange = async {
+                if let (Some(candidates), ProxyConfig::Proxy { auth, spn, .. }, Some(request_url)) = (forwarding_proxies, &network_type, &request_url) {
+                    // A tunnel kept open for an earlier request is no longer needed.
+                    upstream = None;
+                    if expects_continue {
+                        source_write.write_all(CONTINUE_RESPONSE).await?;
+                    }
+                    let mut body = Vec::new();
+                    copy_body(&mut source_reader, &mut body, request_body).await?;
+
+                    let mut head = request.clone();
+                    head.target = request_url.clone();
+                    let (upstream_pool, head, body) = (&self.upstream_pool, &head, &body);
+                    let forwarded = |proxy_uri: String| async move { forward_to_proxy(upstream_pool, &proxy_uri, head, body, auth, spn).await };
+                    let ((dest, response), proxy_uri) = try_upstreams(&self.upstream_pool, candidates, &target_host, forwarded).await?;
+                    let upstream_lease = self.upstream_pool.lease(&proxy_uri);
+                    return Ok((BufReader::new(dest), Some(upstream_lease), response, Some(proxy_uri)));
+                }
+
+                let (mut dest, upstream_lease) = match upstream.take() {
+                    Some(upstream) if upstream.target_host == target_host && upstream.network_type == network_type => {
+                        (upstream.stream, upstream.lease)
+                    }
+                    _ => {
+                        let (dest_socket, upstream_lease) =
+                            open_dest_socket(&self.upstream_pool, &self.settings, network_type.clone(), &target_host, request_url.as_deref()).await?;
+                        (BufReader::new(dest_socket), upstream_lease)
+                    }
+                };
+
+                dest.get_mut().write_all(&request.to_bytes()).await?;
+                if expects_continue {
+                    source_write.write_all(CONTINUE_RESPONSE).await?;
+                }
+                copy_body(&mut source_reader, dest.get_mut(), request_body).await?;
+
+                let response = read_response(&mut dest).await?;
+                Ok::<_, anyhow::Error>((dest, upstream_lease, response, None))
+            };
+
+            let (mut dest, upstream_lease, mut response, pooled_proxy) = match exchange.await {
+                Ok(exchange) => exchange,
+                Err(e) => {
+                    let route = route_name(&network_type, &target_host);
+                    source_write.write_all(&upstream_error_response(&e, &route, &target_host, &self.settings.tickets.status())).await?;
+                    return Err(e);
+                }
+            };
+            while (100..200).contains(&response.status) && response.status != 101 {
+                source_write.write_all(&response.to_bytes()).await?;
+                response = read_response(&mut dest).await?;
+            }
+
+            if upgrade && response.status == 101 {
+                response.strip_hop_by_hop();
+                source_write.write_all(&response.to_bytes()).await?;
+                source_write.write_all(dest.buffer()).await?;
+
+                let (mut dest_read, mut dest_write) = dest.get_mut().split();
+                tokio::try_join!(
+                    tokio::io::copy_buf(&mut source_reader, &mut dest_write),
+                    tokio::io::copy(&mut dest_read, &mut source_write),
+                )?;
+                return Ok(());
+            }
+
+            let response_body = response.body_length(&request.method)?;
+            let upstream_keep_alive = response.persistent() && response_body != BodyLength::UntilClose;
+            let keep_alive = client_keep_alive && response_body != BodyLength::UntilClose;
+
+            response.strip_hop_by_hop();
+            if !keep_alive {
+                response.headers.push(("Connection".to_owned(), "close".to_owned()));
+            }
+            source_write.write_all(&response.to_bytes()).await?;
+            copy_body(&mut dest, &mut source_write, response_body).await?;
+
+            if upstream_keep_alive {
+                match pooled_proxy {
+                    // Anything buffered past the response would be lost, so the connection is only pooled when there is none.
+                    Some(proxy_uri) if dest.buffer().is_empty() => self.upstream_pool.checkin(&proxy_uri, dest.into_inner()),
+                    Some(_) => {}
+                    None => upstream = Some(Upstream { target_host, network_type, stream: dest, lease: upstream_lease }),
+                }
+            }
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
     async fn setup_dest_socket(
         &mut self,
         updated_type: ProxyConfig,
         target_host: &str,
     ) -> Result<(), anyhow::Error> {
-        match updated_type {
-            ProxyConfig::Direct => {
-                println!("💻 -> {}", &target_host);
-                self.dest_socket = Some(connect_with_retry(&target_host).await?);
-                Ok(())
+        if self.dest_socket.is_some() && bypasses_proxy(&updated_type, target_host) {
+            return Ok(());
+        }
+
+        let (dest_socket, upstream_lease) = match direct_address(&updated_type, target_host, self.requested_address.as_deref()) {
+            Some(address) => {
+                println!("💻 -> {} ({}) [NO_PROXY]", address, target_host);
+                (connect_with_retry(address).await?, None)
             }
-            ProxyConfig::Proxy {
-                host,
-                port,
-                no_proxy,
-            } => {
-                let bypass_proxy = no_proxy
-                    .iter()
-                    .any(|no_proxy_host| no_proxy_host.matches_host(&target_host));
-
-                if bypass_proxy {
-                    if self.dest_socket.is_none() {
-                        println!("💻 -> {} [NO_PROXY]", &target_host);
-                        self.dest_socket = Some(connect_with_retry(&target_host).await?);
+            None => open_dest_socket(&self.upstream_pool, &self.settings, updated_type, target_host, None).await?,
+        };
+        self.dest_socket = Some(dest_socket);
+        self.upstream_lease = upstream_lease;
+        Ok(())
+    }
+}
+
+/// An origin connection kept open between requests of a keep-alive client.
+struct Upstream {
+    target_host: String,
+    network_type: ProxyConfig,
+    stream: BufReader<TcpStream>,
+    lease: Option<UpstreamLease>,
+}
+
+/// Reads the next request of a keep-alive client along with the `host:port` it targets and its body framing.
+/// `original_destination` is where the firewall redirected the connection from, if it did.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    source_reader: &mut R,
+    max_header_size: usize,
+    original_destination: Option<SocketAddr>,
+) -> Result<Option<(RequestHead, String, BodyLength)>, anyhow::Error> {
+    let Some(head) = read_head(source_reader, max_header_size).await? else {
+        return Ok(None);
+    };
+
+    let mut request = RequestHead::parse(&head)?;
+    let target_host = request.rewrite_to_origin_form(original_destination)?;
+    let request_body = request.body_length()?;
+    Ok(Some((request, target_host, request_body)))
+}
+
+pub(crate) async fn read_response<R: AsyncBufRead + Unpin>(dest: &mut R) -> Result<ProxyResponse, anyhow::Error> {
+    let head = read_head(dest, MAX_RESPONSE_HEAD_SIZE)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Destination closed connection before responding"))?;
+    ProxyResponse::parse(&head)
+}
+
+/// How `target_host` is reached on this network, as shown to the user in error pages.
+fn route_name(network_type: &ProxyConfig, target_host: &str) -> String {
+    match network_type {
+        ProxyConfig::Direct => "direct".to_owned(),
+        ProxyConfig::Proxy { .. } if bypasses_proxy(network_type, target_host) => "direct (no_proxy)".to_owned(),
+        ProxyConfig::Proxy { upstreams, kind, .. } => {
+            let proxies = upstream_addresses(upstreams).join(", ");
+            match kind {
+                ProxyKind::Http => format!("proxy {}", proxies),
+                ProxyKind::Socks4a { .. } => format!("SOCKS4a proxy {}", proxies),
+                ProxyKind::Socks5(_) => format!("SOCKS5 proxy {}", proxies),
+            }
+        }
+        ProxyConfig::Pac { url, .. } => format!("PAC {}", url),
+    }
+}
+
+fn bypasses_proxy(network_type: &ProxyConfig, target_host: &str) -> bool {
+    match network_type {
+        ProxyConfig::Direct | ProxyConfig::Pac { .. } => false,
+        ProxyConfig::Proxy { no_proxy, .. } => no_proxy
+            .iter()
+            .any(|no_proxy_host| no_proxy_host.matches_host(target_host)),
+    }
+}
+
+/// Whether `target_host` is a bare address that only a `no_proxy` host name could send direct,
+/// in which case the route waits for the TLS server name.
+fn route_needs_server_name(network_type: &ProxyConfig, target_host: &str) -> bool {
+    let ProxyConfig::Proxy { no_proxy, .. } = network_type else {
+        return false;
+    };
+
+    split_host_port(target_host).0.parse::<IpAddr>().is_ok()
+        && !bypasses_proxy(network_type, target_host)
+        && no_proxy.iter().any(|no_proxy_value| matches!(no_proxy_value, NoProxyValue::Host(_)))
+}
+
+/// `host:port` a tunnel to the `target_host` address is routed by: the server name of its
+/// `client_hello` on the same port, or the address itself when there is none.
+fn server_name_host(target_host: &str, client_hello: &[u8]) -> String {
+    match tls::server_name(client_hello) {
+        Some(server_name) => join_host_port(&server_name, split_host_port(target_host).1.unwrap_or("443")),
+        None => target_host.to_owned(),
+    }
+}
+
+/// Address to connect to when a tunnel routed by `routed_host`, a server name, goes direct.
+fn direct_address<'a>(network_type: &ProxyConfig, routed_host: &str, requested_address: Option<&'a str>) -> Option<&'a str> {
+    match network_type {
+        ProxyConfig::Direct => requested_address,
+        ProxyConfig::Proxy { .. } if bypasses_proxy(network_type, routed_host) => requested_address,
+        _ => None,
+    }
+}
+
+fn upstream_addresses(upstreams: &[UpstreamProxy]) -> Vec<String> {
+    upstreams.iter().map(|upstream| join_host_port(&upstream.host, upstream.port)).collect::<Vec<_>>()
+}
+
+/// The proxies of a subnet to try in turn: the healthy ones in policy order, or all of them when none
+/// is healthy. `None` when none is and the subnet then connects directly.
+fn upstream_candidates(upstream_pool: &UpstreamPool, settings: &TunnelSettings, upstreams: &[UpstreamProxy], policy: UpstreamPolicy) -> Option<Vec<String>> {
+    let ordered = upstream_pool.upstream_order(&upstream_addresses(upstreams), policy);
+    let healthy = ordered.iter().filter(|proxy_uri| upstream_pool.is_healthy(proxy_uri)).cloned().collect::<Vec<_>>();
+    if !healthy.is_empty() {
+        Some(healthy)
+    } else if settings.direct_fallback {
+        None
+    } else {
+        Some(ordered)
+    }
+}
+
+/// The HTTP proxies a plain request for `target_host` is sent to as is, over pooled connections,
+/// when its body is small enough. `None` when the request goes direct or through a tunnel.
+fn forwarding_proxies(
+    upstream_pool: &UpstreamPool,
+    settings: &TunnelSettings,
+    network_type: &ProxyConfig,
+    target_host: &str,
+    request_body: BodyLength,
+) -> Option<Vec<String>> {
+    let ProxyConfig::Proxy { upstreams, policy, kind: ProxyKind::Http, .. } = network_type else {
+        return None;
+    };
+    let body_fits = match request_body {
+        BodyLength::Empty => true,
+        BodyLength::Fixed(length) => length <= MAX_FORWARDED_BODY,
+        BodyLength::Chunked | BodyLength::UntilClose => false,
+    };
+    if !body_fits || bypasses_proxy(network_type, target_host) {
+        return None;
+    }
+    upstream_candidates(upstream_pool, settings, upstreams, *policy)
+}
+
+/// Runs `attempt` against each of `candidates` until one gets through, returning its result and the
+/// proxy used. The client only sees an error once every proxy of the subnet failed.
+async fn try_upstreams<T, F, A>(upstream_pool: &UpstreamPool, candidates: Vec<String>, target_host: &str, attempt: A) -> Result<(T, String), anyhow::Error>
+where
+    F: Future<Output = Result<T, anyhow::Error>>,
+    A: Fn(String) -> F,
+{
+    let mut last_error = None;
+    for proxy_uri in candidates {
+        println!("💻 -> {} -> {}", &proxy_uri, target_host);
+        match attempt(proxy_uri.clone()).await {
+            Ok(connected) => {
+                upstream_pool.mark_up(&proxy_uri);
+                return Ok((connected, proxy_uri));
+            }
+            // A proxy that answered is up, the failure is about this request and the others would refuse it too.
+            Err(e) if e.downcast_ref::<UpstreamError>().is_some() => return Err(e),
+            Err(e) => {
+                upstream_pool.mark_down(&proxy_uri);
+                eprintln!("Upstream proxy {} failed: {:#}", proxy_uri, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No upstream proxy configured")))
+}
+
+/// Opens a connection that reaches `target_host`, directly or through a tunnel on an upstream proxy,
+/// along with the lease counting that tunnel against the proxy. `request_url` is given to PAC
+/// scripts for plain HTTP requests.
+async fn open_dest_socket(
+    upstream_pool: &UpstreamPool,
+    settings: &TunnelSettings,
+    network_type: ProxyConfig,
+    target_host: &str,
+    request_url: Option<&str>,
+) -> Result<(TcpStream, Option<UpstreamLease>), anyhow::Error> {
+    let bypass_proxy = bypasses_proxy(&network_type, target_host);
+
+    match network_type {
+        ProxyConfig::Direct => {
+            println!("💻 -> {}", &target_host);
+            Ok((connect_with_retry(target_host).await?, None))
+        }
+        ProxyConfig::Proxy { .. } if bypass_proxy => {
+            println!("💻 -> {} [NO_PROXY]", &target_host);
+            Ok((connect_with_retry(target_host).await?, None))
+        }
+        ProxyConfig::Proxy {
+            upstreams,
+            policy,
+            kind,
+            auth,
+            spn,
+            ..
+        } => {
+            let Some(candidates) = upstream_candidates(upstream_pool, settings, &upstreams, policy) else {
+                println!("💻 -> {} [PROXIES DOWN]", &target_host);
+                return Ok((connect_with_retry(target_host).await?, None));
+            };
+
+            let (kind, auth, spn) = (&kind, &auth, &spn);
+            let tunnel = |proxy_uri: String| async move { connect_through_proxy(upstream_pool, &proxy_uri, kind, target_host, auth, spn).await };
+            let (dest_socket, proxy_uri) = try_upstreams(upstream_pool, candidates, target_host, tunnel).await?;
+            Ok((dest_socket, Some(upstream_pool.lease(&proxy_uri))))
+        }
+        ProxyConfig::Pac { url, auth, spn } => {
+            // Each route the script returns is a fallback for the previous one.
+            let mut last_error = None;
+            for route in settings.pac.find_proxy(&url, target_host, request_url).await? {
+                let connected = match &route {
+                    PacRoute::Direct => {
+                        println!("💻 -> {} [PAC]", &target_host);
+                        connect_with_retry(target_host).await.map_err(anyhow::Error::from)
+                    }
+                    PacRoute::Proxy { host, port, kind } => {
+                        let proxy_uri = &join_host_port(host, port);
+                        println!("💻 -> {} -> {} [PAC]", &proxy_uri, &target_host);
+                        connect_through_proxy(upstream_pool, proxy_uri, kind, target_host, &auth, &spn).await
+                    }
+                };
+
+                match connected {
+                    Ok(dest_socket) => return Ok((dest_socket, None)),
+                    Err(e) => {
+                        eprintln!("PAC route {} failed: {}", route, e);
+                        last_error = Some(e);
                     }
-                    Ok(())
-                } else {
-                    let proxy_uri = &format!("{}:{}", &host, &port);
-                    println!("💻 -> {} -> {}", &proxy_uri, &target_host);
-                    self.dest_socket = Some(connect_to_proxy(proxy_uri, &target_host).await?);
-                    Ok(())
                 }
             }
+            Err(last_error.unwrap_or_else(|| anyhow::anyhow!("PAC script returned no route")))
+        }
+    }
+}
+
+async fn connect_through_proxy(
+    upstream_pool: &UpstreamPool,
+    proxy_uri: &str,
+    kind: &ProxyKind,
+    target_host: &str,
+    auth: &[ProxyAuth],
+    spn: &ProxySpn,
+) -> Result<TcpStream, anyhow::Error> {
+    match kind {
+        ProxyKind::Http => connect_to_proxy(upstream_pool, proxy_uri, target_host, auth, spn).await,
+        ProxyKind::Socks4a { user_id } => {
+            connect_via_socks4a(upstream_pool.checkout(proxy_uri).await?.stream, proxy_uri, target_host, user_id).await
+        }
+        ProxyKind::Socks5(credentials) => {
+            connect_via_socks5(upstream_pool.checkout(proxy_uri).await?.stream, proxy_uri, target_host, credentials.as_ref()).await
         }
     }
 }
@@ -212,4 +820,34 @@ impl ProxyTunnel {
 enum ConnectionState {
     Initializing,
     Forwarding(String),
+    Closed,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::NoProxyValue;
+    use crate::config::{ProxyConfig, ProxyKind, ProxySpn, UpstreamPolicy, UpstreamProxy};
+    use crate::http_proxy::{direct_address, route_needs_server_name, server_name_host};
+    use crate::tls::tests::client_hello;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_connect_to_address_is_routed_by_server_name() {
+        let network_type = ProxyConfig::Proxy {
+            upstreams: vec![UpstreamProxy { host: "proxy.example.com".to_owned(), port: 3128 }],
+            policy: UpstreamPolicy::default(),
+            kind: ProxyKind::Http,
+            no_proxy: vec![NoProxyValue::from_str("intranet.example.com").unwrap()],
+            auth: vec![],
+            spn: ProxySpn::default(),
+        };
+        assert!(route_needs_server_name(&network_type, "10.1.2.3:8443"));
+
+        let routed_host = server_name_host("10.1.2.3:8443", &client_hello("intranet.example.com"));
+        assert_eq!(routed_host, "intranet.example.com:8443");
+        assert_eq!(direct_address(&network_type, &routed_host, Some("10.1.2.3:8443")), Some("10.1.2.3:8443"));
+
+        let routed_host = server_name_host("10.1.2.3:443", &client_hello("www.example.org"));
+        assert_eq!(direct_address(&network_type, &routed_host, Some("10.1.2.3:443")), None);
+    }
 }
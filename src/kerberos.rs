@@ -1,38 +1,126 @@
 pub mod kerberos {
     use base64::engine::general_purpose;
     use base64::Engine;
-    use bytes::Bytes;
-    use cross_krb5::{ClientCtx, InitiateFlags};
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use crate::http::connect_with_retry;
-
-    pub async fn negotiate_with_krb5(proxy_host: &str) -> Result<(), anyhow::Error> {
-
-        let proxy_without_port = {
-            let parts = proxy_host.split(":").collect::<Vec<&str>>();
-            parts.first().map(|e| e.to_owned()).ok_or(anyhow::anyhow!("Could not split proxy on :"))
-        }?;
-
-        let proxy_spn = format!("HTTP/{}", proxy_without_port);
-
-        let (_, token) = ClientCtx::new(InitiateFlags::empty(), None, &proxy_spn, None)?;
-        let token = Bytes::copy_from_slice(&*token);
-        let token_b64 = general_purpose::STANDARD.encode(token);
-
-        let mut proxy_stream = connect_with_retry(proxy_host).await?;
-        proxy_stream.write_all(format!("GET http://{} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Negotiate {}\r\n\r\n", "google.com", "google.com", &token_b64).as_bytes()).await?;
-        proxy_stream.flush().await?;
-
-        let mut read_buffer = [0; 2048];
-        let bytes_read = proxy_stream.read(&mut read_buffer).await?;
-        let data = &read_buffer[..bytes_read];
-
-        if data.starts_with(b"HTTP/1.1 4") || data.starts_with(b"HTTP/1.1 5") {
-            Err(anyhow::anyhow!("Proxy negotiate failed: {}", String::from_utf8_lossy(data)))
-        } else if data.starts_with(b"HTTP/1.1") {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Error: Unexpected proxy negotiate response: {}", String::from_utf8_lossy(data)))
+    use cross_krb5::{ClientCtx, InitiateFlags, PendingClientCtx, Step};
+    use crate::config::ProxySpn;
+    use crate::http::split_host_port;
+    use crate::kerberos_cache::read_ticket_status;
+    use std::collections::HashMap;
+    use std::sync::{LazyLock, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// Client side of a (possibly multi-leg) SPNEGO exchange with the upstream proxy.
+    pub struct SpnegoNegotiator {
+        pending: Option<PendingClientCtx>,
+    }
+
+    impl SpnegoNegotiator {
+        /// Creates the Kerberos context for the proxy and returns the base64 token to put in the
+        /// first `Proxy-Authorization: Negotiate` header. Without a `principal`, the default credentials are used.
+        pub fn new(proxy_spn: &str, principal: Option<&str>) -> Result<(Self, String), anyhow::Error> {
+            let (pending, token) = ClientCtx::new(InitiateFlags::empty(), principal, proxy_spn, None)
+                .map_err(|e| anyhow::anyhow!("{} ({})", e, read_ticket_status()))?;
+            let token_b64 = general_purpose::STANDARD.encode(&*token);
+
+            Ok((Self { pending: Some(pending) }, token_b64))
+        }
+
+        /// Feeds a `Proxy-Authenticate: Negotiate <token>` challenge into the context.
+        /// Returns the next token to send, or `None` once the context is established.
+        pub fn step(&mut self, challenge_b64: &str) -> Result<Option<String>, anyhow::Error> {
+            let pending = self.pending.take().ok_or_else(|| anyhow::anyhow!("Kerberos context is already established"))?;
+            let challenge = general_purpose::STANDARD.decode(challenge_b64.trim())?;
+
+            match pending.step(&challenge)? {
+                Step::Finished((_, token)) => Ok(token.map(|token| general_purpose::STANDARD.encode(&*token))),
+                Step::Continue((pending, token)) => {
+                    self.pending = Some(pending);
+                    Ok(Some(general_purpose::STANDARD.encode(&*token)))
+                }
+            }
         }
+
+        /// Called once the proxy accepted the request. Verifies the proxy's mutual auth token when one was sent.
+        pub fn finish(mut self, challenge_b64: Option<&str>) -> Result<(), anyhow::Error> {
+            match (challenge_b64, self.pending.is_some()) {
+                (Some(challenge), true) => {
+                    self.step(challenge)?;
+                    if self.pending.is_some() {
+                        Err(anyhow::anyhow!("Proxy mutual authentication did not complete the Kerberos context"))
+                    } else {
+                        Ok(())
+                    }
+                }
+                (None, true) => {
+                    println!("⚠️ Proxy accepted Kerberos credentials without a mutual authentication token");
+                    Ok(())
+                }
+                (_, false) => Ok(()),
+            }
+        }
+    }
+
+    /// Derives the service principal of the proxy, `HTTP/<host>` unless overridden.
+    pub async fn proxy_spn(proxy_host: &str, spn: &ProxySpn) -> String {
+        let (host, _) = split_host_port(proxy_host);
+
+        match spn {
+            ProxySpn::Explicit(spn) => spn.clone(),
+            ProxySpn::Host => format!("HTTP/{}", host),
+            ProxySpn::Canonical => format!("HTTP/{}", canonical_host_name(host).await),
+        }
+    }
+
+    const CANONICAL_NAME_TTL: Duration = Duration::from_secs(300);
+
+    static CANONICAL_NAMES: LazyLock<Mutex<HashMap<String, (String, Instant)>>> = LazyLock::new(Default::default);
+
+    /// Resolves the name the KDC knows the proxy by behind DNS aliases and load balancer VIPs:
+    /// forward lookup to the canonical name and address, then reverse lookup of that address.
+    async fn canonical_host_name(host: &str) -> String {
+        if let Some((name, resolved_at)) = CANONICAL_NAMES.lock().unwrap().get(host)
+            && resolved_at.elapsed() < CANONICAL_NAME_TTL
+        {
+            return name.clone();
+        }
+
+        let lookup_host = host.to_owned();
+        let canonical = tokio::task::spawn_blocking(move || resolve_canonical(&lookup_host))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| {
+                eprintln!("⚠️ Could not canonicalize proxy host {}, using it as is for the SPN", host);
+                host.to_owned()
+            });
+
+        CANONICAL_NAMES.lock().unwrap().insert(host.to_owned(), (canonical.clone(), Instant::now()));
+        canonical
+    }
+
+    #[cfg(unix)]
+    fn resolve_canonical(host: &str) -> Option<String> {
+        use dns_lookup::{getaddrinfo, lookup_addr, AddrInfoHints};
+        use std::net::IpAddr;
+        use std::str::FromStr;
+
+        let hints = AddrInfoHints {
+            flags: libc::AI_CANONNAME,
+            ..AddrInfoHints::default()
+        };
+        let mut addresses = getaddrinfo(Some(host), None, Some(hints)).ok()?.filter_map(Result::ok);
+        let first = addresses.next()?;
+
+        let forward_name = first.canonname.clone().filter(|name| !name.is_empty());
+        let reverse_name = lookup_addr(&first.sockaddr.ip())
+            .ok()
+            .filter(|name| IpAddr::from_str(name).is_err());
+
+        reverse_name.or(forward_name).map(|name| name.trim_end_matches('.').to_lowercase())
+    }
+
+    #[cfg(not(unix))]
+    fn resolve_canonical(_host: &str) -> Option<String> {
+        None
     }
 }
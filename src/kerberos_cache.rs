@@ -0,0 +1,443 @@
+use crate::config::{KerberosConfig, KeytabLogin};
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tokio::process::Command;
+use tokio::sync::watch::{self, Receiver};
+
+/// A failed renewal is tried again after this long, doubling up to [`MAX_RENEW_RETRY`].
+const RENEW_RETRY: Duration = Duration::from_secs(60);
+const MAX_RENEW_RETRY: Duration = Duration::from_secs(30 * 60);
+/// How long a keytab login may wait for the KDC.
+const KINIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the credential cache currently holds for the default principal.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TicketStatus {
+    Valid(TicketInfo),
+    Expired(TicketInfo),
+    /// No ticket, or a cache type dagproxy cannot read (KEYRING, KCM, Windows LSA...).
+    Unavailable(String),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TicketInfo {
+    pub principal: String,
+    pub expires: DateTime<Local>,
+    pub renew_till: Option<DateTime<Local>>,
+}
+
+impl TicketInfo {
+    pub fn remaining(&self) -> Duration {
+        (self.expires - Local::now()).to_std().unwrap_or_default()
+    }
+}
+
+impl Display for TicketStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TicketStatus::Valid(ticket) => {
+                write!(f, "TGT for {} valid until {}", &ticket.principal, ticket.expires.format("%Y-%m-%d %H:%M"))?;
+                if let Some(renew_till) = ticket.renew_till {
+                    write!(f, " (renewable until {})", renew_till.format("%Y-%m-%d %H:%M"))?;
+                }
+                Ok(())
+            }
+            TicketStatus::Expired(ticket) => {
+                write!(f, "TGT for {} expired at {}", &ticket.principal, ticket.expires.format("%Y-%m-%d %H:%M"))
+            }
+            TicketStatus::Unavailable(reason) => write!(f, "No Kerberos ticket available: {}", reason),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct TicketWatchHandle {
+    status_receiver: Receiver<TicketStatus>,
+}
+
+impl TicketWatchHandle {
+    pub fn status(&self) -> TicketStatus {
+        self.status_receiver.borrow().clone()
+    }
+}
+
+/// Periodically inspects the credential cache, warns before the TGT expires and runs the
+/// configured renewal when it gets close. Keytab logins are (re)acquired into the private cache
+/// in the background, so an unreachable KDC does not hold the listeners back.
+pub(crate) fn watch_ticket_cache(config: KerberosConfig, keytab_logins: Vec<KeytabLogin>) -> TicketWatchHandle {
+    let (status_sender, status_receiver) = watch::channel(read_ticket_status());
+
+    tokio::spawn(async move {
+        let mut warned_for: Option<DateTime<Local>> = None;
+        // Expiry of the ticket a renewal was last attempted for, `Some(None)` when there was no ticket.
+        let mut renewal_attempted_for: Option<Option<DateTime<Local>>> = None;
+        // When a failed renewal is tried again, along with the delay that was waited.
+        let mut renewal_retry: Option<(Instant, Duration)> = None;
+
+        loop {
+            refresh_keytab_logins(&keytab_logins, &config).await;
+            let status = read_ticket_status();
+            if !status_sender.borrow().eq(&status) {
+                println!("🎫 {}", &status);
+            }
+            status_sender.send_replace(status.clone());
+
+            let ticket = match status {
+                TicketStatus::Valid(ticket) | TicketStatus::Expired(ticket) => Some(ticket),
+                TicketStatus::Unavailable(_) => None,
+            };
+            let expiring = ticket.as_ref().is_none_or(|ticket| ticket.remaining() <= config.warn_before);
+
+            if let Some(ticket) = ticket.as_ref().filter(|ticket| expiring && warned_for != Some(ticket.expires)) {
+                warned_for = Some(ticket.expires);
+                println!(
+                    "⚠️ Kerberos ticket for {} expires in {} minutes, proxy authentication will start failing",
+                    &ticket.principal,
+                    ticket.remaining().as_secs() / 60
+                );
+            }
+
+            let expiry = ticket.as_ref().map(|ticket| ticket.expires);
+            if renewal_attempted_for != Some(expiry) {
+                renewal_retry = None;
+            }
+            let retry_due = renewal_retry.is_some_and(|(retry_at, _)| Instant::now() >= retry_at);
+            if expiring && (renewal_attempted_for != Some(expiry) || retry_due) {
+                renewal_attempted_for = Some(expiry);
+                match renew_ticket(&config).await {
+                    Ok(true) => {
+                        renewal_retry = None;
+                        let status = read_ticket_status();
+                        println!("🎫 {}", &status);
+                        status_sender.send_replace(status);
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        let delay = renewal_retry.map_or(RENEW_RETRY, |(_, delay)| (delay * 2).min(MAX_RENEW_RETRY));
+                        eprintln!("🎫 Kerberos ticket renewal failed, retrying in {} minutes: {}", delay.as_secs() / 60, e);
+                        renewal_retry = Some((Instant::now() + delay, delay));
+                    }
+                }
+            }
+
+            tokio::time::sleep(config.check_interval).await;
+        }
+    });
+
+    TicketWatchHandle { status_receiver }
+}
+
+/// Runs the configured renewal, returning whether one was configured.
+async fn renew_ticket(config: &KerberosConfig) -> Result<bool, anyhow::Error> {
+    let command = match (&config.renew_command, &config.keytab, &config.principal) {
+        (Some(command), _, _) => command.clone(),
+        (None, Some(keytab), Some(principal)) => vec!["kinit".to_owned(), "-k".to_owned(), "-t".to_owned(), keytab.clone(), principal.clone()],
+        (None, Some(_), None) => return Err(anyhow!("A keytab is configured without a principal")),
+        (None, None, _) => return Ok(false),
+    };
+
+    let (program, args) = command.split_first().ok_or_else(|| anyhow!("Empty renew command"))?;
+    println!("🎫 Renewing Kerberos ticket with `{}`", command.join(" "));
+
+    let output = Command::new(program).args(args).output().await?;
+    if output.status.success() {
+        Ok(true)
+    } else {
+        Err(anyhow!("`{}` exited with {}: {}", program, output.status, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// Points the Kerberos library at dagproxy's own credential cache collection, so keytab logins
+/// never touch the user's cache. Must be called before any other thread is started.
+pub(crate) fn use_private_ccache(config: &KerberosConfig) -> Result<(), anyhow::Error> {
+    let cache_dir = private_ccache_dir(config);
+    fs::create_dir_all(&cache_dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&cache_dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    println!("🎫 Using private credential cache collection DIR:{}", cache_dir.display());
+    // SAFETY: called from `main` before the runtime and the network watcher threads exist.
+    unsafe { env::set_var("KRB5CCNAME", format!("DIR:{}", cache_dir.display())) };
+    Ok(())
+}
+
+fn private_ccache_dir(config: &KerberosConfig) -> PathBuf {
+    match &config.private_cache_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => env::temp_dir().join(expand_uid("dagproxy-ccache-%{uid}")),
+    }
+}
+
+/// Each keytab principal gets its own cache in the collection, which GSSAPI picks by principal name.
+fn keytab_ccache_path(login: &KeytabLogin, config: &KerberosConfig) -> PathBuf {
+    let file_name = login
+        .principal
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect::<String>();
+    private_ccache_dir(config).join(format!("tkt_{}", file_name))
+}
+
+async fn refresh_keytab_logins(keytab_logins: &[KeytabLogin], config: &KerberosConfig) {
+    for login in keytab_logins {
+        if let Err(e) = refresh_keytab_login(login, config).await {
+            eprintln!("🎫 Keytab login for {} failed: {}", &login.principal, e);
+        }
+    }
+}
+
+async fn refresh_keytab_login(login: &KeytabLogin, config: &KerberosConfig) -> Result<(), anyhow::Error> {
+    let cache_path = keytab_ccache_path(login, config);
+    let ticket = fs::read(&cache_path).ok().and_then(|data| parse_ccache(&data).ok().flatten());
+    if ticket.is_some_and(|ticket| ticket.remaining() > config.warn_before) {
+        return Ok(());
+    }
+
+    println!("🎫 Acquiring Kerberos ticket for {} from {}", &login.principal, &login.keytab);
+    let kinit = Command::new("kinit")
+        .args(["-k", "-t", &login.keytab, "-c", &format!("DIR::{}", cache_path.display()), &login.principal])
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(KINIT_TIMEOUT, kinit)
+        .await
+        .map_err(|_| anyhow!("kinit did not finish within {} seconds", KINIT_TIMEOUT.as_secs()))??;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("kinit exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+pub fn read_ticket_status() -> TicketStatus {
+    let Some(path) = default_ccache_path() else {
+        return TicketStatus::Unavailable("credential cache type not supported".to_owned());
+    };
+
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => return TicketStatus::Unavailable(format!("{}: {}", path.display(), e)),
+    };
+
+    match parse_ccache(&data) {
+        Ok(Some(ticket)) if ticket.expires > Local::now() => TicketStatus::Valid(ticket),
+        Ok(Some(ticket)) => TicketStatus::Expired(ticket),
+        Ok(None) => TicketStatus::Unavailable(format!("no TGT in {}", path.display())),
+        Err(e) => TicketStatus::Unavailable(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Resolves the FILE (or DIR primary) credential cache the Kerberos library would use:
+/// `KRB5CCNAME`, then `default_ccache_name` from krb5.conf, then `/tmp/krb5cc_<uid>`.
+fn default_ccache_path() -> Option<PathBuf> {
+    let ccache_name = env::var("KRB5CCNAME").ok().or_else(|| {
+        let krb5_conf = env::var("KRB5_CONFIG").unwrap_or_else(|_| "/etc/krb5.conf".to_owned());
+        let krb5_conf = fs::read_to_string(krb5_conf).ok()?;
+        krb5_conf.lines().find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "default_ccache_name").then(|| value.trim().to_owned())
+        })
+    });
+
+    match ccache_name {
+        Some(name) => {
+            let name = expand_uid(&name);
+            match name.split_once(':') {
+                Some(("FILE", path)) => Some(PathBuf::from(path)),
+                Some(("DIR", path)) => match path.strip_prefix(':') {
+                    Some(cache_file) => Some(PathBuf::from(cache_file)),
+                    None => {
+                        let primary = fs::read_to_string(PathBuf::from(path).join("primary")).ok()?;
+                        Some(PathBuf::from(path).join(primary.trim()))
+                    }
+                },
+                Some((cache_type, _)) if cache_type.len() > 1 => None,
+                _ => Some(PathBuf::from(name)),
+            }
+        }
+        None => default_ccache_fallback(),
+    }
+}
+
+#[cfg(unix)]
+fn expand_uid(ccache_name: &str) -> String {
+    // SAFETY: getuid has no preconditions and cannot fail.
+    let uid = unsafe { libc::getuid() };
+    ccache_name.replace("%{uid}", &uid.to_string())
+}
+
+#[cfg(not(unix))]
+fn expand_uid(ccache_name: &str) -> String {
+    ccache_name.to_owned()
+}
+
+#[cfg(unix)]
+fn default_ccache_fallback() -> Option<PathBuf> {
+    Some(PathBuf::from(expand_uid("/tmp/krb5cc_%{uid}")))
+}
+
+#[cfg(not(unix))]
+fn default_ccache_fallback() -> Option<PathBuf> {
+    None
+}
+
+/// Reads the default principal's TGT from a MIT FILE ccache (format versions 3 and 4).
+fn parse_ccache(data: &[u8]) -> Result<Option<TicketInfo>, anyhow::Error> {
+    let mut reader = CcacheReader { data, position: 0 };
+
+    let version = reader.u16()?;
+    if version != 0x0503 && version != 0x0504 {
+        return Err(anyhow!("unsupported credential cache version {:#06x}", version));
+    }
+    if version == 0x0504 {
+        let header_length = reader.u16()? as usize;
+        reader.skip(header_length)?;
+    }
+
+    let (default_realm, default_components) = reader.principal()?;
+    let default_principal = format!("{}@{}", default_components.join("/"), &default_realm);
+
+    while reader.position < data.len() {
+        let _client = reader.principal()?;
+        let (server_realm, server_components) = reader.principal()?;
+
+        reader.u16()?;
+        if version == 0x0503 {
+            reader.u16()?;
+        }
+        reader.octet_string()?;
+
+        let _auth_time = reader.u32()?;
+        let _start_time = reader.u32()?;
+        let end_time = reader.u32()?;
+        let renew_till = reader.u32()?;
+        reader.skip(1 + 4)?;
+
+        for _ in 0..reader.u32()? {
+            reader.u16()?;
+            reader.octet_string()?;
+        }
+        for _ in 0..reader.u32()? {
+            reader.u16()?;
+            reader.octet_string()?;
+        }
+        reader.octet_string()?;
+        reader.octet_string()?;
+
+        let is_tgt = server_components.first().map(String::as_str) == Some("krbtgt")
+            && server_components.get(1) == Some(&default_realm)
+            && server_realm == default_realm;
+
+        if is_tgt {
+            return Ok(Some(TicketInfo {
+                principal: default_principal,
+                expires: to_local(end_time),
+                renew_till: (renew_till > end_time).then(|| to_local(renew_till)),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+fn to_local(timestamp: u32) -> DateTime<Local> {
+    DateTime::from_timestamp(timestamp as i64, 0).unwrap_or_default().into()
+}
+
+struct CcacheReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> CcacheReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], anyhow::Error> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .ok_or_else(|| anyhow!("truncated credential cache"))?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, length: usize) -> Result<(), anyhow::Error> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn octet_string(&mut self) -> Result<&'a [u8], anyhow::Error> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    fn principal(&mut self) -> Result<(String, Vec<String>), anyhow::Error> {
+        let _name_type = self.u32()?;
+        let component_count = self.u32()?;
+        let realm = String::from_utf8_lossy(self.octet_string()?).into_owned();
+        let components = (0..component_count)
+            .map(|_| Ok(String::from_utf8_lossy(self.octet_string()?).into_owned()))
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+        Ok((realm, components))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kerberos_cache::parse_ccache;
+
+    fn octet_string(data: &mut Vec<u8>, value: &[u8]) {
+        data.extend((value.len() as u32).to_be_bytes());
+        data.extend(value);
+    }
+
+    fn principal(data: &mut Vec<u8>, realm: &str, components: &[&str]) {
+        data.extend(1u32.to_be_bytes());
+        data.extend((components.len() as u32).to_be_bytes());
+        octet_string(data, realm.as_bytes());
+        for component in components {
+            octet_string(data, component.as_bytes());
+        }
+    }
+
+    fn credential(data: &mut Vec<u8>, server: &[&str], end_time: u32, renew_till: u32) {
+        principal(data, "EXAMPLE.COM", &["jdoe"]);
+        principal(data, "EXAMPLE.COM", server);
+        data.extend(18u16.to_be_bytes());
+        octet_string(data, &[0; 32]);
+        for time in [1_700_000_000u32, 1_700_000_000, end_time, renew_till] {
+            data.extend(time.to_be_bytes());
+        }
+        data.push(0);
+        data.extend(0u32.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        octet_string(data, b"ticket");
+        octet_string(data, b"");
+    }
+
+    #[test]
+    fn test_parse_ccache_finds_tgt() {
+        let mut data = vec![0x05, 0x04, 0x00, 0x00];
+        principal(&mut data, "EXAMPLE.COM", &["jdoe"]);
+        credential(&mut data, &["HTTP", "proxy.example.com"], 1_700_010_000, 0);
+        credential(&mut data, &["krbtgt", "EXAMPLE.COM"], 1_700_036_000, 1_700_604_800);
+
+        let ticket = parse_ccache(&data).unwrap().unwrap();
+        assert_eq!(ticket.principal, "jdoe@EXAMPLE.COM");
+        assert_eq!(ticket.expires.timestamp(), 1_700_036_000);
+        assert_eq!(ticket.renew_till.unwrap().timestamp(), 1_700_604_800);
+    }
+}
@@ -1,25 +1,48 @@
 mod cert;
 mod config;
 mod config_dto;
+mod digest;
+mod health_check;
+mod hex;
 mod http;
 pub mod http_proxy;
 mod kerberos;
+mod kerberos_cache;
+mod mitm;
 mod network_watcher;
+mod ntlm;
+mod pac;
+mod proxy_auth;
+mod socks;
+mod tls;
+mod transparent;
+mod upstream_pool;
+mod wpad;
 
+use crate::cert::{LeafCache, RootCa};
 use crate::config::Config;
 use crate::config_dto::ConfigDto;
-use http_proxy::HttpProxy;
+use crate::mitm::Interceptor;
+use crate::pac::PacResolver;
+use crate::upstream_pool::UpstreamPool;
+use crate::transparent::TransparentProxy;
+use http_proxy::{HttpProxy, TunnelSettings};
 use netaddr2::{Contains, Netv4Addr};
 use std::fmt::{Display, Formatter};
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::{env, fs};
 use tokio::runtime;
 
 fn main() {
-    print_header();
-
     let env_args: Vec<String> = env::args().collect();
+    // Subcommands print scripts, so they skip the banner.
+    let subcommand = env_args.get(1).filter(|arg| !arg.starts_with('-')).cloned();
+    if subcommand.is_none() {
+        print_header();
+    }
+
     let config_file = env_args
         .windows(2)
         .find_map(|window| {
@@ -29,12 +52,49 @@ fn main() {
                 None
             }
         })
-        .expect("Missing config file path. Usage: -c <path> or --config <path>");
-    println!("Loading configuration from: {}", config_file);
+        .expect("Missing config file path. Usage: [nft-rules [--apply] [--uid <uid>] | export-ca [-o <path>]] -c <path>");
+    if subcommand.is_none() {
+        println!("Loading configuration from: {}", config_file);
+    }
 
     let config_json = fs::read_to_string(config_file).unwrap();
     let config_dto: ConfigDto = serde_json::from_str(&config_json).unwrap();
-    let config: Config = config_dto.into();
+    let config = match Config::try_from(config_dto) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("❌ Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match subcommand.as_deref() {
+        None => {}
+        Some("nft-rules") => {
+            nft_rules(&config, &env_args);
+            return;
+        }
+        Some("export-ca") => {
+            export_ca(&config, &env_args);
+            return;
+        }
+        Some(other) => panic!("Unknown subcommand: {}", other),
+    }
+
+    let keytab_logins = config.keytab_logins();
+    if !keytab_logins.is_empty() {
+        // GSSAPI reads one credential cache per process, the private one would hide the session's.
+        if config.uses_session_ccache() {
+            panic!("NegotiateKeytab cannot be combined with Negotiate from the session's credential cache");
+        }
+        kerberos_cache::use_private_ccache(&config.kerberos).unwrap();
+    }
+
+    // Interception is opt-in, the CA is not even created otherwise.
+    let interceptor = config.mitm.clone().map(|mitm_config| {
+        let root_ca = RootCa::load_or_generate(&config.ca).unwrap();
+        println!("🔓 TLS interception enabled for {}", mitm_config.hosts.join(", "));
+        Arc::new(Interceptor::new(mitm_config, LeafCache::new(root_ca, config.ca.leaf_cache_size)))
+    });
 
     let rt = runtime::Builder::new_current_thread()
         .enable_all()
@@ -42,8 +102,42 @@ fn main() {
         .unwrap();
 
     rt.block_on(async move {
+        let ticket_handle = kerberos_cache::watch_ticket_cache(config.kerberos.clone(), keytab_logins);
+        println!("🎫 {}", ticket_handle.status());
+
         let network_handle = network_watcher::watch_networks(config.clone());
-        let mut http_proxy = HttpProxy::new(network_handle.clone());
+        let upstream_pool = UpstreamPool::new(config.upstream_pool.clone()).with_failover(&config.subnets);
+        let tunnel_settings = TunnelSettings {
+            max_header_size: config.max_header_size,
+            socks_credentials: config.socks.as_ref().and_then(|socks| socks.credentials.clone()),
+            interceptor,
+            pac: PacResolver::new(),
+            direct_fallback: config.health_check.as_ref().is_some_and(|health_check| health_check.fallback_direct),
+            tickets: ticket_handle,
+        };
+        if let Some(health_check) = config.health_check.clone() {
+            health_check::watch_upstreams(health_check, &config.subnets, upstream_pool.clone());
+        }
+
+        if let Some(socks_port) = config.socks.as_ref().and_then(|socks| socks.port) {
+            let mut socks_proxy = HttpProxy::new(network_handle.clone(), upstream_pool.clone(), tunnel_settings.clone());
+            tokio::spawn(async move {
+                if let Err(e) = socks_proxy.start("127.0.0.1".to_owned(), socks_port).await {
+                    eprintln!("SOCKS Proxy stopped: {}", e);
+                }
+            });
+        }
+
+        if let Some(transparent_config) = config.transparent.clone() {
+            let mut transparent_proxy = TransparentProxy::new(network_handle.clone(), upstream_pool.clone(), tunnel_settings.clone(), transparent_config);
+            tokio::spawn(async move {
+                if let Err(e) = transparent_proxy.start().await {
+                    eprintln!("Transparent Proxy stopped: {}", e);
+                }
+            });
+        }
+
+        let mut http_proxy = HttpProxy::new(network_handle.clone(), upstream_pool, tunnel_settings);
 
         http_proxy
             .start("127.0.0.1".to_owned(), config.port)
@@ -52,6 +146,51 @@ fn main() {
     });
 }
 
+/// Prints, or loads with `--apply`, the firewall rules for the `transparent` listener.
+fn nft_rules(config: &Config, env_args: &[String]) {
+    let transparent_config = config.transparent.as_ref().expect("The configuration has no transparent section");
+    // Under sudo the rules should exempt the invoking user, who is the one running dagproxy.
+    let proxy_uid = env_args
+        .windows(2)
+        .find_map(|window| (window[0] == "--uid").then(|| window[1].clone()))
+        .or_else(|| env::var("SUDO_UID").ok())
+        .map(|uid| uid.parse::<u32>().expect("Invalid uid"))
+        .unwrap_or_else(current_uid);
+
+    if env_args.iter().any(|arg| arg == "--apply") {
+        transparent::apply_firewall_rules(transparent_config, proxy_uid).unwrap();
+        println!("🧱 Firewall rules loaded for port {}", transparent_config.port);
+    } else {
+        print!("{}", transparent::firewall_script(transparent_config, proxy_uid));
+    }
+}
+
+/// Prints the root CA certificate, or writes it to the `-o` path, for installing it in trust stores.
+fn export_ca(config: &Config, env_args: &[String]) {
+    let root_ca = RootCa::load_or_generate(&config.ca).unwrap();
+    let output = env_args
+        .windows(2)
+        .find_map(|window| (window[0] == "-o" || window[0] == "--output").then(|| window[1].clone()));
+
+    match output {
+        Some(path) => {
+            fs::write(&path, root_ca.pem()).unwrap();
+            eprintln!("🔐 Root CA written to {}", path);
+        }
+        None => print!("{}", root_ca.pem()),
+    }
+}
+
+#[cfg(unix)]
+fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+#[cfg(not(unix))]
+fn current_uid() -> u32 {
+    0
+}
+
 fn print_header() {
     const HEADER: &str = r#"
   (
@@ -0,0 +1,189 @@
+use crate::cert::LeafCache;
+use crate::config::MitmConfig;
+use crate::http::{BodyLength, CONTINUE_RESPONSE, RequestHead, copy_body, read_head, split_host_port};
+use crate::http_proxy::read_response;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Only HTTP/1.1 is spoken on either side, so requests can be read one at a time.
+const ALPN_HTTP_1_1: &[u8] = b"http/1.1";
+
+/// Terminates the TLS of allowlisted `CONNECT` tunnels with a certificate signed by the local CA
+/// and re-originates it towards the real server, so requests can be logged and given headers.
+pub(crate) struct Interceptor {
+    config: MitmConfig,
+    certificates: LeafCache,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Interceptor {
+    /// Upstream certificates are checked against the system trust store.
+    pub(crate) fn new(config: MitmConfig, certificates: LeafCache) -> Self {
+        let native_certs = rustls_native_certs::load_native_certs();
+        for error in &native_certs.errors {
+            eprintln!("⚠️ Could not load system root certificates: {}", error);
+        }
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(native_certs.certs);
+
+        let mut client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![ALPN_HTTP_1_1.to_vec()];
+
+        Self {
+            config,
+            certificates,
+            client_config: Arc::new(client_config),
+        }
+    }
+
+    /// Whether the host of `target_host` is on the interception allowlist.
+    pub(crate) fn intercepts(&self, target_host: &str) -> bool {
+        let (host, _) = split_host_port(target_host);
+        self.config.hosts.iter().any(|pattern| host_matches(pattern, host))
+    }
+
+    /// TLS handshake with the real server over `upstream`, which already reaches `target_host`.
+    pub(crate) async fn connect(&self, upstream: TcpStream, target_host: &str) -> Result<TlsStream<TcpStream>, anyhow::Error> {
+        let (host, _) = split_host_port(target_host);
+        let server_name = ServerName::try_from(host.to_owned())?;
+        Ok(TlsConnector::from(self.client_config.clone()).connect(server_name, upstream).await?)
+    }
+
+    /// Completes the TLS handshake with a client that was told its tunnel is open, then relays
+    /// its requests to `server`. `pending` is what the client sent after its `CONNECT`.
+    pub(crate) async fn intercept(
+        &self,
+        client: &mut TcpStream,
+        pending: Vec<u8>,
+        server: TlsStream<TcpStream>,
+        target_host: &str,
+        max_header_size: usize,
+    ) -> Result<(), anyhow::Error> {
+        let (host, _) = split_host_port(target_host);
+        let leaf = self.certificates.get(&[host])?;
+        let mut server_config = ServerConfig::builder().with_no_client_auth().with_single_cert(
+            vec![CertificateDer::from(leaf.cert_der.clone())],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(leaf.key_der.clone())),
+        )?;
+        server_config.alpn_protocols = vec![ALPN_HTTP_1_1.to_vec()];
+
+        let client = TlsAcceptor::from(Arc::new(server_config))
+            .accept(Prefixed { prefix: pending, inner: client })
+            .await?;
+
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let mut client_reader = BufReader::new(client_read);
+        let (server_read, mut server_write) = tokio::io::split(server);
+        let mut server_reader = BufReader::new(server_read);
+
+        loop {
+            let Some(head) = read_head(&mut client_reader, max_header_size).await? else {
+                return Ok(());
+            };
+            let mut request = RequestHead::parse(&head)?;
+            let keep_alive = request.keep_alive();
+            let upgrade = request.is_upgrade();
+            let request_body = request.body_length()?;
+            let expects_continue = request.take_expect_continue() && request_body != BodyLength::Empty;
+            for (name, value) in &self.config.headers {
+                request.headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+                request.headers.push((name.clone(), value.clone()));
+            }
+
+            server_write.write_all(&request.to_bytes()).await?;
+            if expects_continue {
+                client_write.write_all(CONTINUE_RESPONSE).await?;
+            }
+            copy_body(&mut client_reader, &mut server_write, request_body).await?;
+
+            let mut response = read_response(&mut server_reader).await?;
+            while (100..200).contains(&response.status) && response.status != 101 {
+                client_write.write_all(&response.to_bytes()).await?;
+                response = read_response(&mut server_reader).await?;
+            }
+            println!("🔓 {} https://{}{} {}", &request.method, target_host, &request.target, response.status);
+            client_write.write_all(&response.to_bytes()).await?;
+
+            if upgrade && response.status == 101 {
+                tokio::try_join!(
+                    tokio::io::copy_buf(&mut client_reader, &mut server_write),
+                    tokio::io::copy_buf(&mut server_reader, &mut client_write),
+                )?;
+                return Ok(());
+            }
+
+            let response_body = response.body_length(&request.method)?;
+            copy_body(&mut server_reader, &mut client_write, response_body).await?;
+            if !keep_alive || !response.persistent() || response_body == BodyLength::UntilClose {
+                client_write.shutdown().await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// `*.example.com` matches any subdomain of `example.com`, other patterns match the host exactly.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.to_ascii_lowercase().ends_with(&format!(".{}", domain.to_ascii_lowercase())),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// A stream whose first reads return bytes already taken off `inner`.
+struct Prefixed<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        let length = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..length]);
+        self.prefix.drain(..length);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mitm::host_matches;
+
+    #[test]
+    fn test_host_patterns() {
+        assert!(host_matches("api.example.com", "API.example.com"));
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+        assert!(!host_matches("api.example.com", "api.example.com.evil.net"));
+    }
+}
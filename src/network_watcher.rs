@@ -3,10 +3,13 @@ use crate::config::SubNetKey;
 use crate::config::SubNetKey::Subnet;
 use netaddr2::{Mask, Netv4Addr};
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use netwatcher::WatchHandle;
-use tokio::sync::watch::Receiver;
+use tokio::runtime::Handle;
+use tokio::sync::watch::{Receiver, Sender};
 use crate::config::ProxyConfig::Direct;
+use crate::wpad;
 
 
 #[derive(Clone)]
@@ -30,15 +33,17 @@ impl NetworkWatchHandle {
 }
 
 pub(crate) fn watch_networks(config: Config) -> NetworkWatchHandle {
-    let network_type = Arc::new(Mutex::new(ProxyConfig::Direct));
     let (notification_sender, notification_receiver) =
         tokio::sync::watch::channel::<ProxyConfig>(ProxyConfig::Direct);
+    let notification_sender = Arc::new(notification_sender);
+    // Discoveries still running when the network changes again must not publish their result.
+    let generation = Arc::new(AtomicU64::new(0));
+    let runtime = Handle::current();
 
-    let cloned_network_type = network_type.clone();
     let handle = netwatcher::watch_interfaces(move |update| {
         // This callback will fire once immediately with the existing state
 
-        let current_subnet = config.subnets.iter().find(|(key, _)| match key {
+        let (current_key, current_subnet) = config.subnets.iter().find(|(key, _)| match key {
             SubNetKey::Default => true,
             Subnet(subnet) => {
                  update.interfaces.iter().any(|(_, interface)| {
@@ -48,26 +53,35 @@ pub(crate) fn watch_networks(config: Config) -> NetworkWatchHandle {
                 })
             },
         })
-            .map(|(_, value)| value)
-            .unwrap_or(&Direct);
+            .map(|(key, value)| (*key, value.clone()))
+            .unwrap_or((SubNetKey::Default, Direct));
+        let current_generation = generation.fetch_add(1, Ordering::SeqCst) + 1;
 
-        if current_subnet.eq(&*cloned_network_type.lock().unwrap()) {
+        let Some(discovery) = config.discover.get(&current_key).cloned() else {
+            publish(&notification_sender, current_subnet);
             return;
-        }
-
-
-        if current_subnet.eq(&Direct) {
-            println!("📡 Network configuration: Direct");
-        } else {
-            println!("📡 Network configuration: Proxied");
-        }
+        };
 
-        {
-            let mut network = cloned_network_type.lock().unwrap();
-            *network = current_subnet.clone();
-        }
+        // The same subnet can lead to another site's proxy, so discovery runs on every change.
+        let notification_sender = notification_sender.clone();
+        let generation = generation.clone();
+        runtime.spawn(async move {
+            let discovered = wpad::discover_pac_url(discovery.devolve_to.as_deref()).await;
+            if generation.load(Ordering::SeqCst) != current_generation {
+                return;
+            }
 
-        notification_sender.send(current_subnet.clone()).unwrap();
+            match discovered {
+                Some(url) => {
+                    println!("📡 Discovered PAC file: {}", url);
+                    publish(&notification_sender, ProxyConfig::Pac { url, auth: discovery.auth, spn: discovery.spn });
+                }
+                None => {
+                    println!("📡 No WPAD PAC file found, using the configured fallback");
+                    publish(&notification_sender, current_subnet);
+                }
+            }
+        });
     })
     .unwrap();
 
@@ -77,6 +91,23 @@ pub(crate) fn watch_networks(config: Config) -> NetworkWatchHandle {
     }
 }
 
+/// Switches tunnels to `network_type` unless it is already the active one.
+fn publish(notification_sender: &Sender<ProxyConfig>, network_type: ProxyConfig) {
+    notification_sender.send_if_modified(|current| {
+        if *current == network_type {
+            return false;
+        }
+
+        if network_type.eq(&Direct) {
+            println!("📡 Network configuration: Direct");
+        } else {
+            println!("📡 Network configuration: Proxied");
+        }
+        *current = network_type;
+        true
+    });
+}
+
 trait ContainsIpV4 {
     fn contains_ipv4(&self, ip: &Ipv4Addr) -> bool;
 }
@@ -0,0 +1,262 @@
+use anyhow::anyhow;
+use base64::engine::general_purpose;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+const AV_EOL: u16 = 0x0000;
+const AV_TIMESTAMP: u16 = 0x0007;
+
+/// Seconds between 1601-01-01 (Windows FILETIME epoch) and 1970-01-01.
+const FILETIME_UNIX_OFFSET_SECS: u64 = 11_644_473_600;
+
+/// NTLMv2 client: produces the Type 1 (negotiate) message and answers the proxy's
+/// Type 2 (challenge) with a Type 3 (authenticate) message.
+pub struct NtlmNegotiator {
+    username: String,
+    domain: String,
+    password: String,
+}
+
+impl NtlmNegotiator {
+    pub fn new(username: &str, domain: Option<&str>, password: String) -> Self {
+        // Accept both `DOMAIN\user` and a separate domain setting.
+        let (domain, username) = match (domain, username.split_once('\\')) {
+            (Some(domain), _) => (domain.to_owned(), username.to_owned()),
+            (None, Some((domain, username))) => (domain.to_owned(), username.to_owned()),
+            (None, None) => (String::new(), username.to_owned()),
+        };
+
+        Self {
+            username,
+            domain,
+            password,
+        }
+    }
+
+    /// Base64 Type 1 message for the first `Proxy-Authorization: NTLM` header.
+    pub fn negotiate_message(&self) -> String {
+        let mut message = Vec::with_capacity(32);
+        message.extend_from_slice(SIGNATURE);
+        message.extend_from_slice(&1u32.to_le_bytes());
+        message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+        // Empty domain and workstation security buffers.
+        message.extend_from_slice(&[0; 16]);
+        general_purpose::STANDARD.encode(message)
+    }
+
+    /// Answers the base64 Type 2 challenge from `Proxy-Authenticate: NTLM <challenge>`.
+    pub fn authenticate_message(&self, challenge_b64: &str) -> Result<String, anyhow::Error> {
+        let challenge = ChallengeMessage::parse(&general_purpose::STANDARD.decode(challenge_b64.trim())?)?;
+
+        let (timestamp, lm_response_zeroed) = match challenge.timestamp() {
+            Some(timestamp) => (timestamp, true),
+            None => (filetime_now(), false),
+        };
+        let client_challenge: [u8; 8] = rand::random();
+
+        let response_key = ntlmv2_hash(&self.username, &self.domain, &self.password);
+        let nt_response = nt_response(&response_key, &challenge.server_challenge, &client_challenge, timestamp, &challenge.target_info);
+        let lm_response = if lm_response_zeroed {
+            vec![0; 24]
+        } else {
+            lm_response(&response_key, &challenge.server_challenge, &client_challenge)
+        };
+
+        let unicode = challenge.flags & NEGOTIATE_UNICODE != 0;
+        let encode = |value: &str| if unicode { utf16le(value) } else { value.as_bytes().to_vec() };
+        let domain = encode(&self.domain);
+        let username = encode(&self.username);
+        let workstation = encode(&workstation_name());
+
+        let flags = (NEGOTIATE_FLAGS & challenge.flags) | NEGOTIATE_NTLM;
+        let payloads: [&[u8]; 6] = [&lm_response, &nt_response, &domain, &username, &workstation, &[]];
+
+        const HEADER_SIZE: usize = 64;
+        let mut message = Vec::with_capacity(HEADER_SIZE + payloads.iter().map(|payload| payload.len()).sum::<usize>());
+        message.extend_from_slice(SIGNATURE);
+        message.extend_from_slice(&3u32.to_le_bytes());
+
+        let mut offset = HEADER_SIZE;
+        for payload in payloads {
+            message.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += payload.len();
+        }
+        message.extend_from_slice(&flags.to_le_bytes());
+
+        for payload in payloads {
+            message.extend_from_slice(payload);
+        }
+
+        Ok(general_purpose::STANDARD.encode(message))
+    }
+}
+
+struct ChallengeMessage {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl ChallengeMessage {
+    fn parse(data: &[u8]) -> Result<Self, anyhow::Error> {
+        if data.len() < 32 || &data[..8] != SIGNATURE || read_u32(data, 8) != 2 {
+            return Err(anyhow!("Invalid NTLM challenge message"));
+        }
+
+        let flags = read_u32(data, 20);
+        let mut server_challenge = [0; 8];
+        server_challenge.copy_from_slice(&data[24..32]);
+
+        let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 && data.len() >= 48 {
+            let length = read_u16(data, 40) as usize;
+            let offset = read_u32(data, 44) as usize;
+            data.get(offset..offset + length)
+                .ok_or_else(|| anyhow!("NTLM challenge target info out of bounds"))?
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            flags,
+            server_challenge,
+            target_info,
+        })
+    }
+
+    /// The `MsvAvTimestamp` AV pair, which the client must echo when present.
+    fn timestamp(&self) -> Option<u64> {
+        let mut position = 0;
+        while position + 4 <= self.target_info.len() {
+            let av_id = read_u16(&self.target_info, position);
+            let av_length = read_u16(&self.target_info, position + 2) as usize;
+            let value = self.target_info.get(position + 4..position + 4 + av_length)?;
+
+            match av_id {
+                AV_EOL => return None,
+                AV_TIMESTAMP if av_length == 8 => {
+                    return Some(u64::from_le_bytes(value.try_into().ok()?));
+                }
+                _ => position += 4 + av_length,
+            }
+        }
+        None
+    }
+}
+
+fn ntlmv2_hash(username: &str, domain: &str, password: &str) -> [u8; 16] {
+    let nt_hash: [u8; 16] = Md4::digest(utf16le(password)).into();
+    let identity = utf16le(&format!("{}{}", username.to_uppercase(), domain));
+    hmac_md5(&nt_hash, &[&identity])
+}
+
+fn nt_response(response_key: &[u8; 16], server_challenge: &[u8; 8], client_challenge: &[u8; 8], timestamp: u64, target_info: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(32 + target_info.len());
+    blob.extend_from_slice(&[0x01, 0x01, 0x00, 0x00]);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let proof = hmac_md5(response_key, &[server_challenge, &blob]);
+    [proof.as_slice(), &blob].concat()
+}
+
+fn lm_response(response_key: &[u8; 16], server_challenge: &[u8; 8], client_challenge: &[u8; 8]) -> Vec<u8> {
+    let proof = hmac_md5(response_key, &[server_challenge, client_challenge]);
+    [proof.as_slice(), client_challenge].concat()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn filetime_now() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs() + FILETIME_UNIX_OFFSET_SECS) * 10_000_000 + since_epoch.subsec_nanos() as u64 / 100
+}
+
+fn workstation_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+        .to_uppercase()
+}
+
+fn utf16le(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hex::hex;
+    use crate::ntlm::{lm_response, nt_response, ntlmv2_hash, utf16le};
+
+    // Test vectors from MS-NLMP 4.2.4 (NTLMv2 authentication).
+    const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+    const CLIENT_CHALLENGE: [u8; 8] = [0xaa; 8];
+
+    #[test]
+    fn test_ntlmv2_hash() {
+        let hash = ntlmv2_hash("User", "Domain", "Password");
+        assert_eq!(hex(&hash), "0c868a403bfd7a93a3001ef22ef02e3f");
+    }
+
+    #[test]
+    fn test_ntlmv2_responses() {
+        let hash = ntlmv2_hash("User", "Domain", "Password");
+
+        let mut target_info = vec![0x02, 0x00, 0x0c, 0x00];
+        target_info.extend(utf16le("Domain"));
+        target_info.extend([0x01, 0x00, 0x0c, 0x00]);
+        target_info.extend(utf16le("Server"));
+        target_info.extend([0x00, 0x00, 0x00, 0x00]);
+
+        let nt = nt_response(&hash, &SERVER_CHALLENGE, &CLIENT_CHALLENGE, 0, &target_info);
+        assert_eq!(hex(&nt[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+
+        let lm = lm_response(&hash, &SERVER_CHALLENGE, &CLIENT_CHALLENGE);
+        assert_eq!(hex(&lm), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+    }
+}
@@ -0,0 +1,563 @@
+use crate::NoProxyValue;
+use crate::config::{ProxyConfig, ProxyKind};
+use crate::http::{MAX_RESPONSE_HEAD_SIZE, ProxyResponse, connect_with_retry, copy_body, join_host_port, read_head, split_host_port};
+use anyhow::anyhow;
+use boa_engine::{Context, JsResult, JsString, JsValue, NativeFunction, Source, js_string};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+/// Scripts are fetched again after this long, the previous one being kept if that fails.
+const PAC_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// A script that could not be fetched is not asked for again before this long.
+const PAC_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest a tunnel waits for `FindProxyForURL`, queueing behind other evaluations included.
+const PAC_EVALUATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Iterations after which a loop of the script is aborted.
+const PAC_LOOP_LIMIT: u64 = 1_000_000;
+/// `dnsResolve` gives up on lookups taking longer, and remembers answers (or their absence) this long.
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The standard PAC helpers that need no access to the host.
+const PAC_UTILS: &str = r#"
+function isPlainHostName(host) {
+    return host.indexOf('.') === -1 && host.indexOf(':') === -1;
+}
+
+function dnsDomainIs(host, domain) {
+    host = host.toLowerCase();
+    domain = domain.toLowerCase();
+    return host.length >= domain.length && host.substring(host.length - domain.length) === domain;
+}
+
+function localHostOrDomainIs(host, hostdom) {
+    return host === hostdom || hostdom.lastIndexOf(host + '.', 0) === 0;
+}
+
+function isResolvable(host) {
+    return dnsResolve(host) !== null;
+}
+
+function dnsDomainLevels(host) {
+    return host.split('.').length - 1;
+}
+
+function convert_addr(ipchars) {
+    var bytes = ipchars.split('.');
+    return ((bytes[0] & 0xff) << 24 | (bytes[1] & 0xff) << 16 | (bytes[2] & 0xff) << 8 | (bytes[3] & 0xff)) >>> 0;
+}
+
+function isInNet(ipaddr, pattern, maskstr) {
+    if (!/^\d+\.\d+\.\d+\.\d+$/.test(ipaddr)) {
+        ipaddr = dnsResolve(ipaddr);
+        if (ipaddr === null) {
+            return false;
+        }
+    }
+    var mask = convert_addr(maskstr);
+    return ((convert_addr(ipaddr) & mask) >>> 0) === ((convert_addr(pattern) & mask) >>> 0);
+}
+
+function shExpMatch(str, shexp) {
+    var pattern = shexp.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.');
+    return new RegExp('^' + pattern + '$').test(str);
+}
+
+function pac_now(args) {
+    var gmt = args.length > 0 && args[args.length - 1] === 'GMT';
+    if (gmt) {
+        args.pop();
+    }
+    var now = new Date();
+    return {
+        gmt: gmt,
+        day: gmt ? now.getUTCDate() : now.getDate(),
+        month: gmt ? now.getUTCMonth() : now.getMonth(),
+        year: gmt ? now.getUTCFullYear() : now.getFullYear(),
+        weekday: gmt ? now.getUTCDay() : now.getDay(),
+        seconds: gmt
+            ? now.getUTCHours() * 3600 + now.getUTCMinutes() * 60 + now.getUTCSeconds()
+            : now.getHours() * 3600 + now.getMinutes() * 60 + now.getSeconds()
+    };
+}
+
+function pac_in_range(value, start, end) {
+    return start <= end ? value >= start && value <= end : value >= start || value <= end;
+}
+
+function weekdayRange() {
+    var days = ['SUN', 'MON', 'TUE', 'WED', 'THU', 'FRI', 'SAT'];
+    var args = Array.prototype.slice.call(arguments);
+    var now = pac_now(args);
+    var start = days.indexOf(args[0]);
+    var end = args.length > 1 ? days.indexOf(args[1]) : start;
+    return start !== -1 && end !== -1 && pac_in_range(now.weekday, start, end);
+}
+
+function dateRange() {
+    var months = ['JAN', 'FEB', 'MAR', 'APR', 'MAY', 'JUN', 'JUL', 'AUG', 'SEP', 'OCT', 'NOV', 'DEC'];
+    var args = Array.prototype.slice.call(arguments);
+    var now = pac_now(args);
+    // Each bound is made of days, months and/or years, compared on the fields it names.
+    function bound(parts) {
+        var fields = {};
+        for (var i = 0; i < parts.length; i++) {
+            if (typeof parts[i] === 'string') {
+                fields.month = months.indexOf(parts[i]);
+            } else if (parts[i] > 31) {
+                fields.year = parts[i];
+            } else {
+                fields.day = parts[i];
+            }
+        }
+        return fields;
+    }
+    function value(fields, date) {
+        return ('year' in fields ? date.year * 10000 : 0) + ('month' in fields ? date.month * 100 : 0) + ('day' in fields ? date.day : 0);
+    }
+    if (args.length === 1) {
+        var single = bound(args);
+        return value(single, single) === value(single, now);
+    }
+    var start = bound(args.slice(0, args.length / 2));
+    var end = bound(args.slice(args.length / 2));
+    return pac_in_range(value(start, now), value(start, start), value(end, end));
+}
+
+function timeRange() {
+    var args = Array.prototype.slice.call(arguments);
+    var now = pac_now(args);
+    switch (args.length) {
+        case 1: return Math.floor(now.seconds / 3600) === args[0];
+        case 2: return pac_in_range(now.seconds, args[0] * 3600, args[1] * 3600 - 1);
+        case 4: return pac_in_range(now.seconds, args[0] * 3600 + args[1] * 60, args[2] * 3600 + args[3] * 60 - 1);
+        case 6: return pac_in_range(now.seconds, args[0] * 3600 + args[1] * 60 + args[2], args[3] * 3600 + args[4] * 60 + args[5]);
+        default: return false;
+    }
+}
+"#;
+
+/// One entry of what `FindProxyForURL` returned, tried in order.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum PacRoute {
+    Direct,
+    Proxy { host: String, port: u32, kind: ProxyKind },
+}
+
+impl Display for PacRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacRoute::Direct => write!(f, "DIRECT"),
+            PacRoute::Proxy { host, port, kind: ProxyKind::Http } => write!(f, "PROXY {}", join_host_port(host, port)),
+            PacRoute::Proxy { host, port, kind: ProxyKind::Socks4a { .. } } => write!(f, "SOCKS4 {}", join_host_port(host, port)),
+            PacRoute::Proxy { host, port, kind: ProxyKind::Socks5(_) } => write!(f, "SOCKS5 {}", join_host_port(host, port)),
+        }
+    }
+}
+
+/// Parses a `FindProxyForURL` result such as `PROXY a:8080; SOCKS5 b:1080; DIRECT`.
+/// Entries dagproxy cannot use are skipped, and an empty result means `DIRECT`.
+pub(crate) fn parse_pac_result(result: &str) -> Vec<PacRoute> {
+    let routes = result
+        .split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            let (kind, default_port) = match parts.next()?.to_ascii_uppercase().as_str() {
+                "DIRECT" => return Some(PacRoute::Direct),
+                "PROXY" | "HTTP" => (ProxyKind::Http, 80),
+                "SOCKS" | "SOCKS4" => (ProxyKind::Socks4a { user_id: String::new() }, 1080),
+                "SOCKS5" => (ProxyKind::Socks5(None), 1080),
+                other => {
+                    eprintln!("Ignoring unsupported PAC route: {}", other);
+                    return None;
+                }
+            };
+
+            let (host, port) = split_host_port(parts.next()?);
+            let port = match port {
+                Some(port) => port.parse().ok()?,
+                None => default_port,
+            };
+            Some(PacRoute::Proxy { host: host.to_owned(), port, kind })
+        })
+        .collect::<Vec<_>>();
+
+    if routes.is_empty() { vec![PacRoute::Direct] } else { routes }
+}
+
+/// PAC script for clients of dagproxy: hosts that the active network reaches without its
+/// upstream proxy go `DIRECT`, everything else through dagproxy at `proxy_address`.
+pub(crate) fn generate_pac(network_type: &ProxyConfig, proxy_address: SocketAddr) -> String {
+    let proxy = format!("PROXY {}", proxy_address);
+    let mut script = "function FindProxyForURL(url, host) {\n".to_owned();
+
+    match network_type {
+        ProxyConfig::Direct => script.push_str("    return \"DIRECT\";\n"),
+        ProxyConfig::Pac { .. } => script.push_str(&format!("    return {:?};\n", proxy)),
+        ProxyConfig::Proxy { no_proxy, .. } => {
+            script.push_str("    var isIpv4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n");
+            for no_proxy_value in no_proxy {
+                match no_proxy_value {
+                    NoProxyValue::Host(no_proxy_host) => script.push_str(&format!(
+                        "    if (host.indexOf({}) !== -1) return \"DIRECT\";\n",
+                        serde_json::to_string(no_proxy_host).expect("strings serialize")
+                    )),
+                    NoProxyValue::Subnet(range) => script.push_str(&format!(
+                        "    if (isIpv4 && isInNet(host, \"{}\", \"{}\")) return \"DIRECT\";\n",
+                        range.addr(),
+                        range.mask()
+                    )),
+                }
+            }
+            script.push_str(&format!("    return {:?};\n", proxy));
+        }
+    }
+
+    script.push_str("}\n");
+    script
+}
+
+/// A compiled PAC script along with the standard helpers.
+pub(crate) struct PacScript {
+    context: Context,
+}
+
+impl PacScript {
+    pub(crate) fn compile(source: &str) -> Result<Self, anyhow::Error> {
+        let mut context = Context::default();
+        context.runtime_limits_mut().set_loop_iteration_limit(PAC_LOOP_LIMIT);
+        context
+            .register_global_callable(js_string!("dnsResolve"), 1, NativeFunction::from_fn_ptr(dns_resolve))
+            .map_err(|e| anyhow!("{}", e))?;
+        context
+            .register_global_callable(js_string!("myIpAddress"), 0, NativeFunction::from_fn_ptr(my_ip_address))
+            .map_err(|e| anyhow!("{}", e))?;
+        context.eval(Source::from_bytes(PAC_UTILS)).map_err(|e| anyhow!("{}", e))?;
+        context
+            .eval(Source::from_bytes(source))
+            .map_err(|e| anyhow!("Invalid PAC script: {}", e))?;
+
+        Ok(Self { context })
+    }
+
+    /// Routes for `url`, whose host is `host`.
+    pub(crate) fn find_proxy(&mut self, url: &str, host: &str) -> Result<Vec<PacRoute>, anyhow::Error> {
+        let context = &mut self.context;
+        let find_proxy_for_url = context
+            .global_object()
+            .get(js_string!("FindProxyForURL"), context)
+            .map_err(|e| anyhow!("{}", e))?;
+        let find_proxy_for_url = find_proxy_for_url
+            .as_callable()
+            .ok_or_else(|| anyhow!("PAC script does not define FindProxyForURL"))?;
+
+        let result = find_proxy_for_url
+            .call(&JsValue::undefined(), &[JsValue::from(JsString::from(url)), JsValue::from(JsString::from(host))], context)
+            .and_then(|result| result.to_string(context))
+            .map_err(|e| anyhow!("FindProxyForURL failed: {}", e))?;
+        Ok(parse_pac_result(&result.to_std_string_escaped()))
+    }
+}
+
+thread_local! {
+    /// Answers of `dnsResolve` on the evaluator thread, with when they were looked up.
+    static DNS_CACHE: RefCell<HashMap<String, (Instant, Option<IpAddr>)>> = RefCell::new(HashMap::new());
+}
+
+fn dns_resolve(_this: &JsValue, args: &[JsValue], context: &mut Context) -> JsResult<JsValue> {
+    let host = args.first().cloned().unwrap_or_default().to_string(context)?.to_std_string_escaped();
+    Ok(match resolve_ipv4(&host) {
+        Some(address) => JsValue::from(JsString::from(address.to_string())),
+        None => JsValue::null(),
+    })
+}
+
+/// First IPv4 address of `host`. Every evaluation waits on the lookups of the script, so they
+/// are cached and a lookup that hangs is left to its own thread.
+fn resolve_ipv4(host: &str) -> Option<IpAddr> {
+    let cached = DNS_CACHE.with_borrow(|cache| cache.get(host).filter(|(resolved_at, _)| resolved_at.elapsed() < DNS_CACHE_TTL).copied());
+    if let Some((_, address)) = cached {
+        return address;
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let lookup = host.to_owned();
+    std::thread::spawn(move || {
+        let address = dns_lookup::lookup_host(&lookup).ok().and_then(|mut addresses| addresses.find(IpAddr::is_ipv4));
+        let _ = sender.send(address);
+    });
+    let address = receiver.recv_timeout(DNS_TIMEOUT).ok().flatten();
+
+    DNS_CACHE.with_borrow_mut(|cache| {
+        cache.retain(|_, (resolved_at, _)| resolved_at.elapsed() < DNS_CACHE_TTL);
+        cache.insert(host.to_owned(), (Instant::now(), address));
+    });
+    address
+}
+
+/// The address of the interface holding the default route, found without sending anything.
+fn my_ip_address(_this: &JsValue, _args: &[JsValue], _context: &mut Context) -> JsResult<JsValue> {
+    let address = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| socket.connect("198.51.100.1:53").map(|_| socket))
+        .and_then(|socket| socket.local_addr())
+        .map(|address| address.ip().to_string())
+        .unwrap_or_else(|_| "127.0.0.1".to_owned());
+    Ok(JsValue::from(JsString::from(address)))
+}
+
+/// Evaluates PAC scripts for the tunnels. The JavaScript engine is single-threaded, so scripts
+/// run on a thread of their own; fetching them stays on the runtime.
+#[derive(Clone)]
+pub(crate) struct PacResolver {
+    requests: mpsc::Sender<PacRequest>,
+    scripts: Arc<Mutex<HashMap<String, FetchedScript>>>,
+}
+
+#[derive(Clone)]
+struct FetchedScript {
+    /// Why the script could not be fetched, when no earlier version was either.
+    source: Result<Arc<String>, String>,
+    fetched_at: Instant,
+}
+
+struct PacRequest {
+    location: String,
+    source: Arc<String>,
+    url: String,
+    host: String,
+    reply: oneshot::Sender<Result<Vec<PacRoute>, anyhow::Error>>,
+}
+
+impl PacResolver {
+    pub(crate) fn new() -> Self {
+        let (requests, receiver) = mpsc::channel::<PacRequest>();
+        std::thread::spawn(move || {
+            let mut compiled = HashMap::new();
+            for request in receiver {
+                let result = evaluate(&mut compiled, &request);
+                let _ = request.reply.send(result);
+            }
+        });
+
+        Self {
+            requests,
+            scripts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Routes the script at `location` gives for `target_host`. Plain HTTP requests pass their
+    /// `request_url`, tunnels only have the host and get an URL made up from it.
+    pub(crate) async fn find_proxy(&self, location: &str, target_host: &str, request_url: Option<&str>) -> Result<Vec<PacRoute>, anyhow::Error> {
+        let source = self.script(location).await?;
+        let (host, port) = split_host_port(target_host);
+        let url_host = if host.contains(':') { format!("[{}]", host) } else { host.to_owned() };
+        let url = match (request_url, port) {
+            (Some(request_url), _) => request_url.to_owned(),
+            (None, Some("443")) => format!("https://{}/", url_host),
+            (None, Some("80") | None) => format!("http://{}/", url_host),
+            (None, Some(port)) => format!("http://{}:{}/", url_host, port),
+        };
+
+        let (reply, routes) = oneshot::channel();
+        self.requests
+            .send(PacRequest { location: location.to_owned(), source, url, host: host.to_owned(), reply })
+            .map_err(|_| anyhow!("PAC evaluator stopped"))?;
+        tokio::time::timeout(PAC_EVALUATION_TIMEOUT, routes)
+            .await
+            .map_err(|_| anyhow!("FindProxyForURL did not answer within {} seconds", PAC_EVALUATION_TIMEOUT.as_secs()))??
+    }
+
+    async fn script(&self, location: &str) -> Result<Arc<String>, anyhow::Error> {
+        let cached = self.scripts.lock().unwrap().get(location).cloned();
+        if let Some(cached) = &cached {
+            let max_age = if cached.source.is_ok() { PAC_REFRESH_INTERVAL } else { PAC_RETRY_INTERVAL };
+            if cached.fetched_at.elapsed() < max_age {
+                return cached.source.clone().map_err(|reason| anyhow!("{}", reason));
+            }
+        }
+
+        let previous = cached.and_then(|cached| cached.source.ok());
+        let (fetched, result) = match fetch_script(location).await {
+            Ok(source) => {
+                if previous.as_ref().is_none_or(|previous| **previous != source) {
+                    println!("📜 Loaded PAC script from {}", location);
+                }
+                let source = Arc::new(source);
+                (Ok(source.clone()), Ok(source))
+            }
+            Err(e) => match previous {
+                Some(previous) => {
+                    eprintln!("Could not refresh PAC script from {}, keeping the previous one: {}", location, e);
+                    (Ok(previous.clone()), Ok(previous))
+                }
+                None => {
+                    let reason = format!("Could not load PAC script from {}: {}", location, e);
+                    (Err(reason.clone()), Err(anyhow!(reason)))
+                }
+            },
+        };
+        let fetched = FetchedScript { source: fetched, fetched_at: Instant::now() };
+        self.scripts.lock().unwrap().insert(location.to_owned(), fetched);
+        result
+    }
+}
+
+/// Runs a request on the evaluator thread, compiling the script again when its source changed.
+fn evaluate(compiled: &mut HashMap<String, (Arc<String>, PacScript)>, request: &PacRequest) -> Result<Vec<PacRoute>, anyhow::Error> {
+    let up_to_date = compiled
+        .get(&request.location)
+        .is_some_and(|(source, _)| Arc::ptr_eq(source, &request.source));
+    if !up_to_date {
+        let script = PacScript::compile(&request.source)?;
+        compiled.insert(request.location.clone(), (request.source.clone(), script));
+    }
+
+    let (_, script) = compiled.get_mut(&request.location).expect("compiled above");
+    script.find_proxy(&request.url, &request.host)
+}
+
+/// Reads a PAC script from an `http(s)://` or `file://` URL, or a local path.
+pub(crate) async fn fetch_script(location: &str) -> Result<String, anyhow::Error> {
+    let (scheme, rest) = match location.split_once("://") {
+        Some((scheme, rest)) => (scheme.to_ascii_lowercase(), rest),
+        None => return Ok(tokio::fs::read_to_string(location).await?),
+    };
+    if scheme == "file" {
+        return Ok(tokio::fs::read_to_string(rest).await?);
+    }
+
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (host, port) = split_host_port(authority);
+    let default_port = match scheme.as_str() {
+        "http" => "80",
+        "https" => "443",
+        _ => return Err(anyhow!("Unsupported PAC URL scheme: {}", scheme)),
+    };
+    let stream = connect_with_retry(&join_host_port(host, port.unwrap_or(default_port))).await?;
+
+    if scheme == "http" {
+        return http_get(stream, authority, path).await;
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    let tls_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let stream = TlsConnector::from(Arc::new(tls_config))
+        .connect(ServerName::try_from(host.to_owned())?, stream)
+        .await?;
+    http_get(stream, authority, path).await
+}
+
+async fn http_get<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, authority: &str, path: &str) -> Result<String, anyhow::Error> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/x-ns-proxy-autoconfig, */*\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let head = read_head(&mut reader, MAX_RESPONSE_HEAD_SIZE)
+        .await?
+        .ok_or_else(|| anyhow!("Server closed the connection before responding"))?;
+    let response = ProxyResponse::parse(&head)?;
+    if response.status != 200 {
+        return Err(anyhow!("Server answered {} {}", response.status, response.reason));
+    }
+
+    let mut body = Vec::new();
+    copy_body(&mut reader, &mut body, response.body_length("GET")?).await?;
+    Ok(String::from_utf8(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::NoProxyValue;
+    use crate::config::{ProxyAuth, ProxyConfig, ProxyKind, ProxySpn, UpstreamPolicy, UpstreamProxy};
+    use crate::pac::{PacRoute, PacScript, generate_pac, parse_pac_result};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_pac_result() {
+        assert_eq!(
+            parse_pac_result("PROXY proxy.corp:8080; SOCKS5 [::1]:1080;HTTPS secure.corp; DIRECT"),
+            vec![
+                PacRoute::Proxy { host: "proxy.corp".to_owned(), port: 8080, kind: ProxyKind::Http },
+                PacRoute::Proxy { host: "::1".to_owned(), port: 1080, kind: ProxyKind::Socks5(None) },
+                PacRoute::Direct,
+            ]
+        );
+        assert_eq!(parse_pac_result(""), vec![PacRoute::Direct]);
+    }
+
+    #[test]
+    fn test_find_proxy_with_standard_helpers() {
+        let mut script = PacScript::compile(
+            r#"
+            function dnsResolve(host) {
+                return host === "build.corp" ? "10.0.0.7" : null;
+            }
+
+            function FindProxyForURL(url, host) {
+                if (isPlainHostName(host) || dnsDomainIs(host, ".intranet.corp") || isInNet(host, "10.0.0.0", "255.0.0.0")) {
+                    return "DIRECT";
+                }
+                if (shExpMatch(url, "https://*.example.com/*") && weekdayRange("SUN", "SAT") && timeRange(0, 24)) {
+                    return "PROXY secure.corp:3128; DIRECT";
+                }
+                return "PROXY proxy.corp:8080";
+            }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(script.find_proxy("http://wiki/", "wiki").unwrap(), vec![PacRoute::Direct]);
+        assert_eq!(script.find_proxy("https://app.intranet.corp/", "app.intranet.corp").unwrap(), vec![PacRoute::Direct]);
+        assert_eq!(script.find_proxy("http://10.1.2.3/", "10.1.2.3").unwrap(), vec![PacRoute::Direct]);
+        assert_eq!(script.find_proxy("http://build.corp/", "build.corp").unwrap(), vec![PacRoute::Direct]);
+        assert_eq!(
+            script.find_proxy("https://www.example.com/", "www.example.com").unwrap(),
+            vec![PacRoute::Proxy { host: "secure.corp".to_owned(), port: 3128, kind: ProxyKind::Http }, PacRoute::Direct]
+        );
+        assert_eq!(
+            script.find_proxy("http://11.1.2.3/", "11.1.2.3").unwrap(),
+            vec![PacRoute::Proxy { host: "proxy.corp".to_owned(), port: 8080, kind: ProxyKind::Http }]
+        );
+    }
+
+    #[test]
+    fn test_runaway_script_is_aborted() {
+        let mut script = PacScript::compile("function FindProxyForURL(url, host) { while (true) {} }").unwrap();
+        assert!(script.find_proxy("http://example.com/", "example.com").is_err());
+    }
+
+    #[test]
+    fn test_generated_pac_follows_no_proxy() {
+        let network_type = ProxyConfig::Proxy {
+            upstreams: vec![UpstreamProxy { host: "proxy.corp".to_owned(), port: 8080 }],
+            policy: UpstreamPolicy::Failover,
+            kind: ProxyKind::Http,
+            no_proxy: vec![NoProxyValue::Host("intranet.corp".to_owned()), NoProxyValue::from_str("10.0.0.0/8").unwrap()],
+            auth: vec![ProxyAuth::Negotiate(None)],
+            spn: ProxySpn::Host,
+        };
+        let mut script = PacScript::compile(&generate_pac(&network_type, "127.0.0.1:3232".parse().unwrap())).unwrap();
+
+        let dagproxy = vec![PacRoute::Proxy { host: "127.0.0.1".to_owned(), port: 3232, kind: ProxyKind::Http }];
+        assert_eq!(script.find_proxy("https://wiki.intranet.corp/", "wiki.intranet.corp").unwrap(), vec![PacRoute::Direct]);
+        assert_eq!(script.find_proxy("http://10.1.2.3/", "10.1.2.3").unwrap(), vec![PacRoute::Direct]);
+        assert_eq!(script.find_proxy("http://11.1.2.3/", "11.1.2.3").unwrap(), dagproxy);
+        assert_eq!(script.find_proxy("https://www.example.com/", "www.example.com").unwrap(), dagproxy);
+    }
+}
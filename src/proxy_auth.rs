@@ -0,0 +1,361 @@
+use crate::config::{ProxyAuth, ProxyCredentials};
+use crate::digest::DigestChallenge;
+use crate::kerberos::kerberos::SpnegoNegotiator;
+use crate::ntlm::NtlmNegotiator;
+use anyhow::anyhow;
+use base64::engine::general_purpose;
+use base64::Engine;
+
+/// One `<scheme> <params>` challenge from a `Proxy-Authenticate` header.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct AuthChallenge {
+    pub scheme: String,
+    pub params: String,
+}
+
+/// Answers proxy challenges for one authentication scheme, possibly over several legs
+/// of the same keep-alive connection.
+pub(crate) trait ProxyAuthenticator: Send {
+    fn scheme(&self) -> &'static str;
+
+    /// Returns the `Proxy-Authorization` value for the next request, given the params of the
+    /// latest challenge for this scheme. `None` sends the request without the header.
+    fn respond(&mut self, challenge: Option<&str>) -> Result<Option<String>, anyhow::Error>;
+
+    /// Called once the proxy accepted the request, with the challenge params it sent along, if any.
+    fn finish(&mut self, _challenge: Option<&str>) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Splits `Proxy-Authenticate` header values into challenges. A single header may carry several
+/// comma separated challenges, e.g. `Negotiate, NTLM, Basic realm="proxy"`.
+pub(crate) fn parse_challenges<'a>(header_values: impl Iterator<Item = &'a str>) -> Vec<AuthChallenge> {
+    let mut challenges: Vec<AuthChallenge> = Vec::new();
+
+    for header_value in header_values {
+        for part in split_outside_quotes(header_value) {
+            let part = part.trim();
+            if part.is_empty() {
+                continue;
+            }
+
+            let (first_word, rest) = part.split_once(' ').unwrap_or((part, ""));
+            let starts_challenge = !first_word.contains('=');
+
+            match challenges.last_mut() {
+                Some(challenge) if !starts_challenge => {
+                    if !challenge.params.is_empty() {
+                        challenge.params.push_str(", ");
+                    }
+                    challenge.params.push_str(part);
+                }
+                _ => challenges.push(AuthChallenge {
+                    scheme: first_word.to_owned(),
+                    params: rest.trim().to_owned(),
+                }),
+            }
+        }
+    }
+
+    challenges
+}
+
+fn split_outside_quotes(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                parts.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn scheme_of(auth: &ProxyAuth) -> &'static str {
+    match auth {
+        ProxyAuth::Negotiate(_) => "Negotiate",
+        ProxyAuth::Ntlm(_) => "NTLM",
+        ProxyAuth::Digest(_) => "Digest",
+        ProxyAuth::Basic(_) => "Basic",
+        ProxyAuth::None => "None",
+    }
+}
+
+fn strength_of(auth: &ProxyAuth) -> u8 {
+    match auth {
+        ProxyAuth::Negotiate(_) => 4,
+        ProxyAuth::Ntlm(_) => 3,
+        ProxyAuth::Digest(_) => 2,
+        ProxyAuth::Basic(_) => 1,
+        ProxyAuth::None => 0,
+    }
+}
+
+/// Builds authenticators for the configured schemes the proxy offers, strongest first.
+/// `method` and `uri` are those of the request, covered by Digest responses.
+pub(crate) fn select_authenticators(
+    configured: &[ProxyAuth],
+    challenges: &[AuthChallenge],
+    proxy_spn: &str,
+    method: &str,
+    uri: &str,
+) -> Vec<Box<dyn ProxyAuthenticator>> {
+    let mut offered = configured
+        .iter()
+        .filter(|auth| {
+            matches!(auth, ProxyAuth::None)
+                || challenges.iter().any(|challenge| challenge.scheme.eq_ignore_ascii_case(scheme_of(auth)))
+        })
+        .collect::<Vec<_>>();
+    offered.sort_by_key(|auth| std::cmp::Reverse(strength_of(auth)));
+
+    offered
+        .into_iter()
+        .map(|auth| -> Box<dyn ProxyAuthenticator> {
+            match auth {
+                ProxyAuth::Negotiate(login) => Box::new(NegotiateAuthenticator::new(
+                    proxy_spn,
+                    login.as_ref().map(|login| login.principal.clone()),
+                )),
+                ProxyAuth::Ntlm(credentials) => Box::new(NtlmAuthenticator::new(credentials.clone())),
+                ProxyAuth::Digest(credentials) => Box::new(DigestAuthenticator::new(credentials.clone(), method, uri)),
+                ProxyAuth::Basic(credentials) => Box::new(BasicAuthenticator::new(credentials.clone())),
+                ProxyAuth::None => Box::new(NoopAuthenticator::default()),
+            }
+        })
+        .collect()
+}
+
+pub(crate) struct NegotiateAuthenticator {
+    proxy_spn: String,
+    principal: Option<String>,
+    negotiator: Option<SpnegoNegotiator>,
+}
+
+impl NegotiateAuthenticator {
+    pub fn new(proxy_spn: &str, principal: Option<String>) -> Self {
+        Self {
+            proxy_spn: proxy_spn.to_owned(),
+            principal,
+            negotiator: None,
+        }
+    }
+}
+
+impl ProxyAuthenticator for NegotiateAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "Negotiate"
+    }
+
+    fn respond(&mut self, challenge: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+        let token = match (self.negotiator.as_mut(), challenge) {
+            (None, _) => {
+                let (negotiator, token) = SpnegoNegotiator::new(&self.proxy_spn, self.principal.as_deref())?;
+                self.negotiator = Some(negotiator);
+                token
+            }
+            (Some(negotiator), Some(challenge)) => negotiator
+                .step(challenge)?
+                .ok_or_else(|| anyhow!("Kerberos context established but proxy still requires authentication"))?,
+            (Some(_), None) => return Err(anyhow!("Proxy rejected Kerberos credentials")),
+        };
+        Ok(Some(format!("Negotiate {}", token)))
+    }
+
+    fn finish(&mut self, challenge: Option<&str>) -> Result<(), anyhow::Error> {
+        match self.negotiator.take() {
+            Some(negotiator) => negotiator.finish(challenge),
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) struct NtlmAuthenticator {
+    credentials: ProxyCredentials,
+    negotiator: Option<NtlmNegotiator>,
+    authenticated: bool,
+}
+
+impl NtlmAuthenticator {
+    pub fn new(credentials: ProxyCredentials) -> Self {
+        Self {
+            credentials,
+            negotiator: None,
+            authenticated: false,
+        }
+    }
+}
+
+impl ProxyAuthenticator for NtlmAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "NTLM"
+    }
+
+    fn respond(&mut self, challenge: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+        if self.authenticated {
+            return Err(anyhow!("Proxy rejected NTLM credentials for user {}", &self.credentials.username));
+        }
+
+        match (&self.negotiator, challenge) {
+            (Some(negotiator), Some(challenge)) => {
+                self.authenticated = true;
+                Ok(Some(format!("NTLM {}", negotiator.authenticate_message(challenge)?)))
+            }
+            (Some(_), None) => Err(anyhow!("Proxy did not answer with an NTLM challenge")),
+            (None, _) => {
+                let negotiator = NtlmNegotiator::new(
+                    &self.credentials.username,
+                    self.credentials.domain.as_deref(),
+                    self.credentials.password.resolve()?,
+                );
+                let negotiate_message = negotiator.negotiate_message();
+                self.negotiator = Some(negotiator);
+                Ok(Some(format!("NTLM {}", negotiate_message)))
+            }
+        }
+    }
+}
+
+pub(crate) struct DigestAuthenticator {
+    credentials: ProxyCredentials,
+    method: String,
+    uri: String,
+    attempts: u8,
+}
+
+impl DigestAuthenticator {
+    pub fn new(credentials: ProxyCredentials, method: &str, uri: &str) -> Self {
+        Self {
+            credentials,
+            method: method.to_owned(),
+            uri: uri.to_owned(),
+            attempts: 0,
+        }
+    }
+}
+
+impl ProxyAuthenticator for DigestAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "Digest"
+    }
+
+    fn respond(&mut self, challenge: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+        let challenge = challenge.ok_or_else(|| anyhow!("Proxy did not send a Digest challenge"))?;
+        let challenge = DigestChallenge::parse(challenge)?;
+
+        // A second challenge is only expected when the nonce went stale.
+        self.attempts += 1;
+        if self.attempts > 1 && !challenge.is_stale() {
+            return Err(anyhow!("Proxy rejected Digest credentials for user {}", &self.credentials.username));
+        }
+
+        let password = self.credentials.password.resolve()?;
+        Ok(Some(challenge.authorization(&self.credentials.username, &password, &self.method, &self.uri)))
+    }
+}
+
+pub(crate) struct BasicAuthenticator {
+    credentials: ProxyCredentials,
+    sent: bool,
+}
+
+impl BasicAuthenticator {
+    pub fn new(credentials: ProxyCredentials) -> Self {
+        Self { credentials, sent: false }
+    }
+}
+
+impl ProxyAuthenticator for BasicAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "Basic"
+    }
+
+    fn respond(&mut self, _challenge: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+        if self.sent {
+            return Err(anyhow!("Proxy rejected Basic credentials for user {}", &self.credentials.username));
+        }
+        self.sent = true;
+
+        let user_pass = format!("{}:{}", &self.credentials.username, self.credentials.password.resolve()?);
+        Ok(Some(format!("Basic {}", general_purpose::STANDARD.encode(user_pass))))
+    }
+}
+
+/// Retries the request once without credentials, for proxies that remember an out-of-band login.
+#[derive(Default)]
+pub(crate) struct NoopAuthenticator {
+    retried: bool,
+}
+
+impl ProxyAuthenticator for NoopAuthenticator {
+    fn scheme(&self) -> &'static str {
+        "None"
+    }
+
+    fn respond(&mut self, _challenge: Option<&str>) -> Result<Option<String>, anyhow::Error> {
+        if self.retried {
+            return Err(anyhow!("Proxy still requires authentication"));
+        }
+        self.retried = true;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ProxyAuth, ProxyCredentials, Secret};
+    use crate::proxy_auth::{parse_challenges, select_authenticators, AuthChallenge};
+
+    #[test]
+    fn test_parse_challenges() {
+        let challenges = parse_challenges(
+            ["Negotiate, NTLM", r#"Basic realm="Squid, proxy", Digest realm="proxy", nonce="abc==", qop="auth""#, "Negotiate YIIB=="].into_iter(),
+        );
+
+        assert_eq!(
+            challenges,
+            vec![
+                AuthChallenge { scheme: "Negotiate".to_owned(), params: "".to_owned() },
+                AuthChallenge { scheme: "NTLM".to_owned(), params: "".to_owned() },
+                AuthChallenge { scheme: "Basic".to_owned(), params: r#"realm="Squid, proxy""#.to_owned() },
+                AuthChallenge { scheme: "Digest".to_owned(), params: r#"realm="proxy", nonce="abc==", qop="auth""#.to_owned() },
+                AuthChallenge { scheme: "Negotiate".to_owned(), params: "YIIB==".to_owned() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_strongest_offered_scheme_first() {
+        let credentials = ProxyCredentials {
+            username: "user".to_owned(),
+            domain: None,
+            password: Secret::Plain("password".to_owned()),
+        };
+        let configured = vec![
+            ProxyAuth::Basic(credentials.clone()),
+            ProxyAuth::Digest(credentials.clone()),
+            ProxyAuth::Ntlm(credentials),
+            ProxyAuth::Negotiate(None),
+        ];
+        let challenges = parse_challenges(["NTLM", r#"Basic realm="proxy""#].into_iter());
+
+        let schemes = select_authenticators(&configured, &challenges, "HTTP/proxy", "CONNECT", "example.com:443")
+            .iter()
+            .map(|authenticator| authenticator.scheme())
+            .collect::<Vec<_>>();
+
+        assert_eq!(schemes, vec!["NTLM", "Basic"]);
+    }
+}
@@ -0,0 +1,403 @@
+use crate::config::SocksCredentials;
+use crate::http::{UpstreamError, join_host_port, split_host_port};
+use anyhow::anyhow;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub(crate) const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+pub(crate) const SOCKS5_VERSION: u8 = 0x05;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Server side of a SOCKS5 (RFC 1928) handshake: negotiates the authentication method and reads
+/// the `CONNECT` request, returning its `host:port`.
+pub(crate) async fn handshake(stream: &mut TcpStream, credentials: Option<&SocksCredentials>) -> Result<String, anyhow::Error> {
+    let version = stream.read_u8().await?;
+    if version != SOCKS5_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", version));
+    }
+
+    let method_count = stream.read_u8().await?;
+    let mut methods = vec![0; method_count as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match credentials {
+        Some(_) => METHOD_USERNAME_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS5_VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(anyhow!("Client offered no acceptable authentication method"));
+    }
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+    if let Some(credentials) = credentials {
+        // RFC 1929 username/password sub-negotiation.
+        let version = stream.read_u8().await?;
+        if version != USERNAME_PASSWORD_VERSION {
+            return Err(anyhow!("Unsupported username/password negotiation version {}", version));
+        }
+        let username = read_short_string(stream).await?;
+        let password = read_short_string(stream).await?;
+
+        if username != credentials.username || password != credentials.password.resolve()? {
+            stream.write_all(&[USERNAME_PASSWORD_VERSION, 0x01]).await?;
+            return Err(anyhow!("Invalid credentials for SOCKS user {}", username));
+        }
+        stream.write_all(&[USERNAME_PASSWORD_VERSION, 0x00]).await?;
+    }
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS5_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", version));
+    }
+
+    let host = match address_type {
+        ADDRESS_IPV4 => {
+            let mut address = [0; 4];
+            stream.read_exact(&mut address).await?;
+            Ipv4Addr::from(address).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut address = [0; 16];
+            stream.read_exact(&mut address).await?;
+            Ipv6Addr::from(address).to_string()
+        }
+        ADDRESS_DOMAIN => read_short_string(stream).await?,
+        _ => {
+            stream.write_all(&reply(REPLY_ADDRESS_TYPE_NOT_SUPPORTED)).await?;
+            return Err(anyhow!("Unsupported SOCKS address type {}", address_type));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if command != COMMAND_CONNECT {
+        stream.write_all(&reply(REPLY_COMMAND_NOT_SUPPORTED)).await?;
+        return Err(anyhow!("Unsupported SOCKS command {}", command));
+    }
+
+    Ok(join_host_port(&host, port))
+}
+
+/// Server side of a SOCKS4/4a handshake, returning the `host:port` of the `CONNECT` request.
+/// SOCKS4 has no authentication, so it is refused when SOCKS credentials are configured.
+pub(crate) async fn socks4_handshake(stream: &mut TcpStream, credentials: Option<&SocksCredentials>) -> Result<String, anyhow::Error> {
+    let mut request = [0; 8];
+    stream.read_exact(&mut request).await?;
+    let [version, command, port_high, port_low, a, b, c, d] = request;
+    if version != SOCKS4_VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {}", version));
+    }
+
+    let _user_id = read_null_terminated(stream).await?;
+    // 0.0.0.x with x != 0 means SOCKS4a: the host name follows the user id.
+    let host = match [a, b, c, d] {
+        [0, 0, 0, x] if x != 0 => read_null_terminated(stream).await?,
+        address => Ipv4Addr::from(address).to_string(),
+    };
+
+    if command != COMMAND_CONNECT || credentials.is_some() {
+        stream.write_all(&socks4_reply(SOCKS4_REJECTED)).await?;
+        return Err(anyhow!("Refused SOCKS4 command {} to {}", command, host));
+    }
+
+    Ok(join_host_port(&host, u16::from_be_bytes([port_high, port_low])))
+}
+
+async fn read_null_terminated(stream: &mut TcpStream) -> Result<String, anyhow::Error> {
+    let mut value = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(String::from_utf8(value)?),
+            _ if value.len() >= 255 => return Err(anyhow!("SOCKS4 field longer than 255 bytes")),
+            byte => value.push(byte),
+        }
+    }
+}
+
+async fn read_short_string(stream: &mut TcpStream) -> Result<String, anyhow::Error> {
+    let length = stream.read_u8().await?;
+    let mut value = vec![0; length as usize];
+    stream.read_exact(&mut value).await?;
+    Ok(String::from_utf8(value)?)
+}
+
+/// The reply to a `CONNECT` once the tunnel is set up, or why it could not be.
+pub(crate) fn connect_reply(version: u8, connected: &Result<(), anyhow::Error>) -> Vec<u8> {
+    match (version, connected) {
+        (SOCKS4_VERSION, Ok(())) => socks4_reply(SOCKS4_GRANTED).to_vec(),
+        (SOCKS4_VERSION, Err(_)) => socks4_reply(SOCKS4_REJECTED).to_vec(),
+        (_, Ok(())) => reply(REPLY_SUCCEEDED).to_vec(),
+        (_, Err(e)) => reply(reply_code(e)).to_vec(),
+    }
+}
+
+/// A reply without a meaningful bound address, which clients of a `CONNECT` do not use.
+fn reply(reply_code: u8) -> [u8; 10] {
+    [SOCKS5_VERSION, reply_code, 0x00, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+fn socks4_reply(reply_code: u8) -> [u8; 8] {
+    [0x00, reply_code, 0, 0, 0, 0, 0, 0]
+}
+
+fn reply_code(error: &anyhow::Error) -> u8 {
+    if let Some(upstream_error) = error.downcast_ref::<UpstreamError>() {
+        return match upstream_error {
+            UpstreamError::ProxyAuthentication { .. } | UpstreamError::ProxyStatus(403) => REPLY_NOT_ALLOWED,
+            UpstreamError::ProxyStatus(_) => REPLY_HOST_UNREACHABLE,
+        };
+    }
+
+    match error.downcast_ref::<io::Error>().map(|e| e.kind()) {
+        Some(io::ErrorKind::PermissionDenied) => REPLY_NOT_ALLOWED,
+        Some(io::ErrorKind::ConnectionRefused) => REPLY_CONNECTION_REFUSED,
+        Some(io::ErrorKind::TimedOut) => REPLY_TTL_EXPIRED,
+        Some(io::ErrorKind::NetworkUnreachable) => REPLY_NETWORK_UNREACHABLE,
+        Some(io::ErrorKind::HostUnreachable) => REPLY_HOST_UNREACHABLE,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// Opens a tunnel to `target_host` over `stream`, a connection to an upstream SOCKS5 proxy.
+/// Host names are sent as is so the proxy resolves them, as it would for an HTTP `CONNECT`.
+pub(crate) async fn connect_via_socks5(
+    mut stream: TcpStream,
+    proxy_host: &str,
+    target_host: &str,
+    credentials: Option<&SocksCredentials>,
+) -> Result<TcpStream, anyhow::Error> {
+    let (host, port) = split_target(target_host)?;
+
+    let methods: &[u8] = match credentials {
+        Some(_) => &[METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+        None => &[METHOD_NO_AUTH],
+    };
+    stream.write_all(&[&[SOCKS5_VERSION, methods.len() as u8], methods].concat()).await?;
+
+    let mut method_selection = [0; 2];
+    stream.read_exact(&mut method_selection).await?;
+    match (method_selection[1], credentials) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USERNAME_PASSWORD, Some(credentials)) => {
+            let password = credentials.password.resolve()?;
+            let mut negotiation = vec![USERNAME_PASSWORD_VERSION];
+            push_short_string(&mut negotiation, &credentials.username)?;
+            push_short_string(&mut negotiation, &password)?;
+            stream.write_all(&negotiation).await?;
+
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                let message = format!("SOCKS5 proxy {} rejected the credentials of {}", proxy_host, &credentials.username);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, message).into());
+            }
+        }
+        _ => return Err(anyhow!("SOCKS5 proxy {} accepts none of the offered authentication methods", proxy_host)),
+    }
+
+    let mut request = vec![SOCKS5_VERSION, COMMAND_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => {
+            request.push(ADDRESS_IPV4);
+            request.extend_from_slice(&address.octets());
+        }
+        Ok(IpAddr::V6(address)) => {
+            request.push(ADDRESS_IPV6);
+            request.extend_from_slice(&address.octets());
+        }
+        Err(_) => {
+            request.push(ADDRESS_DOMAIN);
+            push_short_string(&mut request, host)?;
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut response = [0; 4];
+    stream.read_exact(&mut response).await?;
+    let bound_address_length = match response[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+        address_type => return Err(anyhow!("SOCKS5 proxy replied with unknown address type {}", address_type)),
+    };
+    let mut bound_address = vec![0; bound_address_length + 2];
+    stream.read_exact(&mut bound_address).await?;
+
+    match response[1] {
+        REPLY_SUCCEEDED => Ok(stream),
+        reply_code => Err(io::Error::new(
+            reply_error_kind(reply_code),
+            format!("SOCKS5 proxy {} could not connect to {} (reply {})", proxy_host, target_host, reply_code),
+        )
+        .into()),
+    }
+}
+
+/// Opens a tunnel to `target_host` over `stream`, a connection to an upstream SOCKS4a proxy,
+/// which resolves host names itself.
+pub(crate) async fn connect_via_socks4a(
+    mut stream: TcpStream,
+    proxy_host: &str,
+    target_host: &str,
+    user_id: &str,
+) -> Result<TcpStream, anyhow::Error> {
+    let (host, port) = split_target(target_host)?;
+
+    let mut request = vec![SOCKS4_VERSION, COMMAND_CONNECT];
+    request.extend_from_slice(&port.to_be_bytes());
+    match host.parse::<Ipv4Addr>() {
+        Ok(address) => {
+            request.extend_from_slice(&address.octets());
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+        }
+        Err(_) => {
+            // 0.0.0.x tells a SOCKS4a proxy that the host name follows the user id.
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+            request.extend_from_slice(host.as_bytes());
+            request.push(0);
+        }
+    }
+    stream.write_all(&request).await?;
+
+    let mut response = [0; 8];
+    stream.read_exact(&mut response).await?;
+    match response[1] {
+        SOCKS4_GRANTED => Ok(stream),
+        reply_code => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SOCKS4a proxy {} could not connect to {} (reply {:#x})", proxy_host, target_host, reply_code),
+        )
+        .into()),
+    }
+}
+
+fn split_target(target_host: &str) -> Result<(&str, u16), anyhow::Error> {
+    match split_host_port(target_host) {
+        (host, Some(port)) => Ok((host, port.parse().map_err(|_| anyhow!("Invalid port in {}", target_host))?)),
+        (_, None) => Err(anyhow!("No port in {}", target_host)),
+    }
+}
+
+fn push_short_string(buffer: &mut Vec<u8>, value: &str) -> Result<(), anyhow::Error> {
+    let length = u8::try_from(value.len()).map_err(|_| anyhow!("{} is longer than 255 bytes", value))?;
+    buffer.push(length);
+    buffer.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn reply_error_kind(reply_code: u8) -> io::ErrorKind {
+    match reply_code {
+        REPLY_NOT_ALLOWED => io::ErrorKind::PermissionDenied,
+        REPLY_NETWORK_UNREACHABLE => io::ErrorKind::NetworkUnreachable,
+        REPLY_HOST_UNREACHABLE => io::ErrorKind::HostUnreachable,
+        REPLY_CONNECTION_REFUSED => io::ErrorKind::ConnectionRefused,
+        REPLY_TTL_EXPIRED => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Secret, SocksCredentials};
+    use crate::socks::{connect_via_socks5, handshake, reply, socks4_handshake, REPLY_SUCCEEDED};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_handshake_with_credentials_and_domain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+            assert_eq!(stream.read_u16().await.unwrap(), 0x0502);
+
+            stream.write_all(b"\x01\x04user\x06secret").await.unwrap();
+            assert_eq!(stream.read_u16().await.unwrap(), 0x0100);
+
+            stream.write_all(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb").await.unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let credentials = SocksCredentials {
+            username: "user".to_owned(),
+            password: Secret::Plain("secret".to_owned()),
+        };
+
+        assert_eq!(handshake(&mut stream, Some(&credentials)).await.unwrap(), "example.com:443");
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_upstream_keeps_host_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_host = listener.local_addr().unwrap().to_string();
+        let credentials = SocksCredentials {
+            username: "user".to_owned(),
+            password: Secret::Plain("secret".to_owned()),
+        };
+        let server_credentials = credentials.clone();
+
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(handshake(&mut stream, Some(&server_credentials)).await.unwrap(), "intranet.example.com:22");
+            stream.write_all(&reply(REPLY_SUCCEEDED)).await.unwrap();
+            stream.write_all(b"SSH-2.0").await.unwrap();
+        });
+
+        let proxy_stream = TcpStream::connect(&proxy_host).await.unwrap();
+        let mut stream = connect_via_socks5(proxy_stream, &proxy_host, "intranet.example.com:22", Some(&credentials)).await.unwrap();
+
+        let mut banner = [0; 7];
+        stream.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"SSH-2.0");
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks4a_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(b"\x04\x01\x00\x50\x00\x00\x00\x01me\x00example.com\x00").await.unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(socks4_handshake(&mut stream, None).await.unwrap(), "example.com:80");
+        client.await.unwrap();
+    }
+}
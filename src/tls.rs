@@ -0,0 +1,155 @@
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub(crate) const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_HOST_NAME: u8 = 0x00;
+const RECORD_HEADER_SIZE: usize = 5;
+const MAX_RECORD_SIZE: usize = 16384 + 2048;
+
+/// Reads the first TLS record sent by a client, which carries its ClientHello.
+pub(crate) async fn read_client_hello<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>, anyhow::Error> {
+    let mut record = vec![0; RECORD_HEADER_SIZE];
+    stream.read_exact(&mut record).await?;
+    if record[0] != CONTENT_TYPE_HANDSHAKE {
+        return Err(anyhow!("Not a TLS handshake record"));
+    }
+
+    let length = u16::from_be_bytes([record[3], record[4]]) as usize;
+    if length > MAX_RECORD_SIZE {
+        return Err(anyhow!("TLS record of {} bytes is too large", length));
+    }
+
+    record.resize(RECORD_HEADER_SIZE + length, 0);
+    stream.read_exact(&mut record[RECORD_HEADER_SIZE..]).await?;
+    Ok(record)
+}
+
+/// Size of the ClientHello record at the start of `data`, `None` while it has not fully arrived.
+pub(crate) fn client_hello_length(data: &[u8]) -> Result<Option<usize>, anyhow::Error> {
+    if data.first().is_some_and(|content_type| *content_type != CONTENT_TYPE_HANDSHAKE) {
+        return Err(anyhow!("Not a TLS handshake record"));
+    }
+    if data.len() < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if length > MAX_RECORD_SIZE {
+        return Err(anyhow!("TLS record of {} bytes is too large", length));
+    }
+    Ok((data.len() >= RECORD_HEADER_SIZE + length).then_some(RECORD_HEADER_SIZE + length))
+}
+
+/// The host name requested through SNI in a ClientHello record, if the client sent one.
+pub(crate) fn server_name(record: &[u8]) -> Option<String> {
+    let mut data = record.get(RECORD_HEADER_SIZE..)?;
+
+    if take_u8(&mut data)? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    take(&mut data, 3)?; // handshake length
+    take(&mut data, 2 + 32)?; // client version and random
+    let session_id_length = take_u8(&mut data)? as usize;
+    take(&mut data, session_id_length)?;
+    let cipher_suites_length = take_u16(&mut data)? as usize;
+    take(&mut data, cipher_suites_length)?;
+    let compression_methods_length = take_u8(&mut data)? as usize;
+    take(&mut data, compression_methods_length)?;
+
+    let extensions_length = take_u16(&mut data)? as usize;
+    let mut extensions = take(&mut data, extensions_length)?;
+    while !extensions.is_empty() {
+        let extension_type = take_u16(&mut extensions)?;
+        let extension_length = take_u16(&mut extensions)? as usize;
+        let mut extension = take(&mut extensions, extension_length)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let list_length = take_u16(&mut extension)? as usize;
+        let mut names = take(&mut extension, list_length)?;
+        while !names.is_empty() {
+            let name_type = take_u8(&mut names)?;
+            let name_length = take_u16(&mut names)? as usize;
+            let name = take(&mut names, name_length)?;
+            if name_type == SERVER_NAME_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if data.len() < length {
+        return None;
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+    Some(taken)
+}
+
+fn take_u8(data: &mut &[u8]) -> Option<u8> {
+    take(data, 1).map(|byte| byte[0])
+}
+
+fn take_u16(data: &mut &[u8]) -> Option<u16> {
+    take(data, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::tls::{client_hello_length, read_client_hello, server_name};
+
+    pub(crate) fn client_hello(host: &str) -> Vec<u8> {
+        let mut server_name_list = vec![0x00];
+        server_name_list.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        server_name_list.extend_from_slice(host.as_bytes());
+
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]; // ec_point_formats
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(server_name_list.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(server_name_list.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name_list);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[tokio::test]
+    async fn test_server_name_from_client_hello() {
+        let data = [client_hello("intranet.example.com"), b"next record".to_vec()].concat();
+        let mut reader: &[u8] = &data;
+
+        let record = read_client_hello(&mut reader).await.unwrap();
+
+        assert_eq!(server_name(&record).as_deref(), Some("intranet.example.com"));
+        assert_eq!(reader, b"next record");
+        assert_eq!(server_name(&record[..record.len() - 3]), None);
+    }
+
+    #[test]
+    fn test_client_hello_length_waits_for_whole_record() {
+        let record = client_hello("intranet.example.com");
+
+        assert_eq!(client_hello_length(&record[..3]).unwrap(), None);
+        assert_eq!(client_hello_length(&record[..record.len() - 1]).unwrap(), None);
+        assert_eq!(client_hello_length(&[record.clone(), b"more".to_vec()].concat()).unwrap(), Some(record.len()));
+        assert!(client_hello_length(b"GET / HTTP/1.1\r\n").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::{TransparentConfig, TransparentMode};
+use crate::http::join_host_port;
+use crate::http_proxy::{ProxyTunnel, TunnelSettings};
+use crate::network_watcher::NetworkWatchHandle;
+use crate::upstream_pool::UpstreamPool;
+use anyhow::anyhow;
+use std::io;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::process::{Command, Stdio};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Listener for connections redirected by the firewall (nftables `REDIRECT` or `TPROXY`), for apps
+/// that ignore proxy settings. The host name is taken from the TLS SNI or HTTP `Host` header
+/// when there is one, so `no_proxy` and the upstream proxy see names rather than addresses.
+pub struct TransparentProxy {
+    network_watcher: NetworkWatchHandle,
+    upstream_pool: UpstreamPool,
+    settings: TunnelSettings,
+    config: TransparentConfig,
+}
+
+impl TransparentProxy {
+    pub(crate) fn new(
+        network_watcher: NetworkWatchHandle,
+        upstream_pool: UpstreamPool,
+        settings: TunnelSettings,
+        config: TransparentConfig,
+    ) -> Self {
+        Self {
+            network_watcher,
+            upstream_pool,
+            settings,
+            config,
+        }
+    }
+
+    pub async fn start(&mut self) -> Result<(), anyhow::Error> {
+        let address = join_host_port(&self.config.listen, self.config.port);
+        let listener = match self.config.mode {
+            TransparentMode::Redirect => TcpListener::bind(&address).await?,
+            TransparentMode::Tproxy => bind_transparent(address.parse()?)?,
+        };
+
+        println!("👂 Transparent Proxy listening on {} ({:?})", &address, self.config.mode);
+
+        loop {
+            match listener.accept().await {
+                Ok((source_socket, _)) => {
+                    let destination = match self.config.mode {
+                        TransparentMode::Redirect => original_destination(&source_socket),
+                        // TPROXY leaves the packets untouched, the socket is bound to the address the client asked for.
+                        TransparentMode::Tproxy => source_socket.local_addr().map(|address| SocketAddr::new(address.ip().to_canonical(), address.port())),
+                    };
+
+                    let original_destination = match destination {
+                        Ok(destination) if !self.is_listener(&source_socket, destination) => destination,
+                        Ok(_) => {
+                            eprintln!("Refusing connection made straight to the transparent listener");
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("Could not recover original destination: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let network_watcher = self.network_watcher.clone();
+                    let upstream_pool = self.upstream_pool.clone();
+                    let settings = self.settings.clone();
+
+                    tokio::spawn(async move {
+                        let mut proxy_tunnel = ProxyTunnel::new(source_socket, network_watcher, upstream_pool, settings)
+                            .transparent(original_destination);
+                        proxy_tunnel.start().await;
+                    });
+                }
+                Err(err) => {
+                    eprintln!(
+                        "An error has occurred accepting incoming connection: {}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    fn is_listener(&self, source_socket: &TcpStream, destination: SocketAddr) -> bool {
+        match self.config.mode {
+            TransparentMode::Redirect => Some(destination) == source_socket.local_addr().ok(),
+            // Every TPROXY socket has the client's destination as local address, so the port alone
+            // would also refuse other hosts serving on the listener's port.
+            TransparentMode::Tproxy => {
+                destination.port() as u32 == self.config.port && is_listen_address(&self.config.listen, destination.ip())
+            }
+        }
+    }
+}
+
+/// Whether `ip` is where the listener is bound: the `listen` address, or any of the host's own
+/// addresses when listening on all of them.
+fn is_listen_address(listen: &str, ip: IpAddr) -> bool {
+    match listen.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(listen_ip) if !listen_ip.is_unspecified() => listen_ip.to_canonical() == ip,
+        _ => {
+            ip.is_loopback()
+                || netwatcher::list_interfaces()
+                    .map(|interfaces| {
+                        interfaces.values().any(|interface| {
+                            interface.ipv4_ips().any(|local_ip| IpAddr::V4(*local_ip) == ip)
+                                || interface.ipv6_ips().any(|local_ip| IpAddr::V6(*local_ip) == ip)
+                        })
+                    })
+                    .unwrap_or(false)
+        }
+    }
+}
+
+/// Listener accepting connections for any address, as handed over by a `TPROXY` rule.
+/// Needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+fn bind_transparent(address: SocketAddr) -> Result<TcpListener, io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    let (level, option) = match address {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+
+    let enabled: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, option, &enabled as *const _ as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_transparent(_address: SocketAddr) -> Result<TcpListener, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TPROXY is only supported on Linux"))
+}
+
+/// nftables ruleset sending the configured ports to the transparent listener. Connections made
+/// by `proxy_uid`, the user dagproxy runs as, are left alone so they can reach the upstream.
+pub(crate) fn nftables_rules(config: &TransparentConfig, proxy_uid: u32) -> String {
+    let ports = config.ports.iter().map(|port| port.to_string()).collect::<Vec<_>>().join(", ");
+    let port = config.port;
+    let mark = config.mark;
+
+    let chains = match config.mode {
+        TransparentMode::Redirect => format!(
+            "    chain output {{
+        type nat hook output priority -100; policy accept;
+        meta skuid {proxy_uid} return
+        fib daddr type local return
+        tcp dport {{ {ports} }} redirect to :{port}
+    }}
+"
+        ),
+        TransparentMode::Tproxy => format!(
+            "    chain prerouting {{
+        type filter hook prerouting priority mangle; policy accept;
+        fib daddr type local return
+        meta l4proto tcp socket transparent 1 meta mark set {mark} accept
+        meta nfproto ipv4 tcp dport {{ {ports} }} tproxy ip to :{port} meta mark set {mark} accept
+        meta nfproto ipv6 tcp dport {{ {ports} }} tproxy ip6 to :{port} meta mark set {mark} accept
+    }}
+
+    chain output {{
+        type route hook output priority mangle; policy accept;
+        meta skuid {proxy_uid} return
+        fib daddr type local return
+        tcp dport {{ {ports} }} meta mark set {mark}
+    }}
+"
+        ),
+    };
+
+    // Declaring the table before deleting it makes the ruleset safe to load again.
+    format!("table inet dagproxy\ndelete table inet dagproxy\n\ntable inet dagproxy {{\n{chains}}}\n")
+}
+
+/// `ip` commands routing marked packets to the local stack, which `TPROXY` relies on.
+pub(crate) fn policy_routing_commands(config: &TransparentConfig) -> Vec<Vec<String>> {
+    if config.mode != TransparentMode::Tproxy {
+        return Vec::new();
+    }
+
+    let mark = config.mark.to_string();
+    let table = config.route_table.to_string();
+    [("-4", "0.0.0.0/0"), ("-6", "::/0")]
+        .into_iter()
+        .flat_map(|(family, everything)| {
+            [
+                vec![family, "rule", "add", "fwmark", &mark, "lookup", &table],
+                vec![family, "route", "add", "local", everything, "dev", "lo", "table", &table],
+            ]
+        })
+        .map(|args| args.into_iter().map(str::to_owned).collect())
+        .collect()
+}
+
+/// Shell script setting up the firewall for the transparent listener.
+pub(crate) fn firewall_script(config: &TransparentConfig, proxy_uid: u32) -> String {
+    let mut script = format!("#!/bin/sh\nnft -f - <<'EOF'\n{}EOF\n", nftables_rules(config, proxy_uid));
+    for args in policy_routing_commands(config) {
+        script.push_str(&format!("ip {}\n", args.join(" ")));
+    }
+    script
+}
+
+/// Loads the firewall rules for the transparent listener, needs root.
+pub(crate) fn apply_firewall_rules(config: &TransparentConfig, proxy_uid: u32) -> Result<(), anyhow::Error> {
+    let mut nft = Command::new("nft").args(["-f", "-"]).stdin(Stdio::piped()).spawn()?;
+    nft.stdin.take().ok_or_else(|| anyhow!("nft stdin is not available"))?
+        .write_all(nftables_rules(config, proxy_uid).as_bytes())?;
+    let status = nft.wait()?;
+    if !status.success() {
+        return Err(anyhow!("nft failed with {}", status));
+    }
+
+    for args in policy_routing_commands(config) {
+        let status = Command::new("ip").args(&args).status()?;
+        // The rule and route survive until reboot, so they may already be there from a previous run.
+        if !status.success() {
+            eprintln!("⚠️ ip {} failed with {}", args.join(" "), status);
+        }
+    }
+
+    Ok(())
+}
+
+/// Destination the client connected to before the `REDIRECT` rule rewrote it.
+#[cfg(target_os = "linux")]
+pub(crate) fn original_destination(stream: &TcpStream) -> Result<SocketAddr, io::Error> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+    use std::os::fd::AsRawFd;
+
+    // From linux/netfilter_ipv4.h and linux/netfilter_ipv6/ip6_tables.h.
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    let fd = stream.as_raw_fd();
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => {
+            let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            let mut length = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(fd, libc::SOL_IP, SO_ORIGINAL_DST, &mut address as *mut _ as *mut libc::c_void, &mut length)
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+            Ok(SocketAddr::from((ip, u16::from_be(address.sin_port))))
+        }
+        SocketAddr::V6(_) => {
+            let mut address: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            let mut length = size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(fd, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST, &mut address as *mut _ as *mut libc::c_void, &mut length)
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let ip = Ipv6Addr::from(address.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(address.sin6_port), address.sin6_flowinfo, address.sin6_scope_id)))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn original_destination(_stream: &TcpStream) -> Result<SocketAddr, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Transparent mode is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{TransparentConfig, TransparentMode};
+    use crate::transparent::{firewall_script, is_listen_address, nftables_rules};
+
+    #[test]
+    fn test_firewall_rules_for_each_mode() {
+        let mut config = TransparentConfig {
+            listen: "::".to_owned(),
+            port: 3233,
+            mode: TransparentMode::Redirect,
+            ports: vec![80, 443],
+            mark: 1,
+            route_table: 100,
+        };
+
+        let redirect = firewall_script(&config, 1000);
+        assert!(redirect.contains("meta skuid 1000 return"));
+        assert!(redirect.contains("tcp dport { 80, 443 } redirect to :3233"));
+        assert!(!redirect.contains("ip -4 rule"));
+
+        config.mode = TransparentMode::Tproxy;
+        let tproxy = firewall_script(&config, 1000);
+        assert!(tproxy.contains("tproxy ip to :3233 meta mark set 1 accept"));
+        assert!(tproxy.contains("tproxy ip6 to :3233 meta mark set 1 accept"));
+        assert!(tproxy.contains("ip -6 route add local ::/0 dev lo table 100\n"));
+        assert!(nftables_rules(&config, 1000).starts_with("table inet dagproxy\ndelete table inet dagproxy\n"));
+    }
+
+    #[test]
+    fn test_tproxy_listener_is_matched_by_address() {
+        assert!(is_listen_address("10.0.0.5", "10.0.0.5".parse().unwrap()));
+        assert!(!is_listen_address("10.0.0.5", "93.184.216.34".parse().unwrap()));
+        assert!(is_listen_address("::", "127.0.0.1".parse().unwrap()));
+        assert!(!is_listen_address("0.0.0.0", "93.184.216.34".parse().unwrap()));
+    }
+}
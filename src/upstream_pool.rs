@@ -0,0 +1,331 @@
+use crate::config::{PoolConfig, ProxyAuth, ProxyConfig, SubNetKey, UpstreamPolicy};
+use crate::http::{connect_once, connect_with_retry, join_host_port};
+use crate::proxy_auth::{select_authenticators, AuthChallenge, ProxyAuthenticator};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// How long a proxy that could not be reached is tried only after the others.
+const UPSTREAM_DOWN_FOR: Duration = Duration::from_secs(30);
+/// The one connection attempt made to a proxy that shares its subnet with others.
+const FAILOVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Connections to upstream proxies, keyed by proxy `host:port`.
+///
+/// A `CONNECT` uses its connection up as a tunnel, so the idle ones are those opened ahead of time
+/// and those that carried a plain HTTP request the proxy kept alive. Idle connections are handed out
+/// before opening new ones. The pool also remembers which scheme last authenticated against each
+/// proxy, so the next request and the connections opened ahead of time carry credentials right away,
+/// and which proxies recently failed or carry the most tunnels, to pick between several of them.
+#[derive(Clone)]
+pub(crate) struct UpstreamPool {
+    config: PoolConfig,
+    /// Proxies with alternatives in their subnet, where retrying a dead one only delays the next.
+    failover_hosts: Arc<HashSet<String>>,
+    state: Arc<Mutex<PoolState>>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: HashMap<String, Vec<IdleConnection>>,
+    warming: HashSet<String>,
+    preferred_schemes: HashMap<String, PreferredScheme>,
+    down_until: HashMap<String, Instant>,
+    /// Latest active health check of each proxy, `None` when it failed.
+    probes: HashMap<String, Option<Duration>>,
+    open_tunnels: HashMap<String, usize>,
+    /// Round-robin position, keyed by the subnet's list of proxies.
+    rotations: HashMap<String, usize>,
+}
+
+impl PoolState {
+    /// Neither recently failed nor down at its last health check.
+    fn is_healthy(&self, proxy_host: &str, now: Instant) -> bool {
+        self.down_until.get(proxy_host).is_none_or(|until| *until <= now)
+            && self.probes.get(proxy_host).is_none_or(|latency| latency.is_some())
+    }
+
+    fn latency(&self, proxy_host: &str) -> Duration {
+        self.probes.get(proxy_host).copied().flatten().unwrap_or(Duration::MAX)
+    }
+}
+
+/// The scheme a proxy last accepted, with what is needed to authenticate new connections to it.
+struct PreferredScheme {
+    scheme: &'static str,
+    auth: Vec<ProxyAuth>,
+    proxy_spn: String,
+}
+
+struct IdleConnection {
+    connection: PooledConnection,
+    idle_since: Instant,
+}
+
+/// A connection to an upstream proxy and how far it got with authentication.
+pub(crate) struct PooledConnection {
+    pub stream: TcpStream,
+    pub auth: ConnectionAuth,
+}
+
+pub(crate) enum ConnectionAuth {
+    Unauthenticated,
+    /// The proxy accepted a request on it, which NTLM and Negotiate proxies remember for the connection.
+    Authenticated,
+    /// Opened ahead of time with the first `Proxy-Authorization` of the preferred scheme already computed.
+    Prepared(Box<dyn ProxyAuthenticator>, Option<String>),
+}
+
+impl UpstreamPool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            failover_hosts: Arc::new(HashSet::new()),
+            state: Arc::new(Mutex::new(PoolState::default())),
+        }
+    }
+
+    /// Connects only once, quickly, to the proxies of subnets that have several.
+    pub fn with_failover(mut self, subnets: &[(SubNetKey, ProxyConfig)]) -> Self {
+        let failover_hosts = subnets.iter().flat_map(|(_, proxy_config)| match proxy_config {
+            ProxyConfig::Proxy { upstreams, .. } if upstreams.len() > 1 => upstreams.as_slice(),
+            _ => &[],
+        });
+        self.failover_hosts = Arc::new(failover_hosts.map(|upstream| join_host_port(&upstream.host, upstream.port)).collect());
+        self
+    }
+
+    /// Returns an idle connection to the proxy if a live one is available, otherwise opens one.
+    pub async fn checkout(&self, proxy_host: &str) -> Result<PooledConnection, io::Error> {
+        let connection = match self.take_idle(proxy_host) {
+            Some(connection) => connection,
+            None => {
+                let stream = if self.failover_hosts.contains(proxy_host) {
+                    connect_once(proxy_host, FAILOVER_CONNECT_TIMEOUT).await?
+                } else {
+                    connect_with_retry(proxy_host).await?
+                };
+                PooledConnection { stream, auth: ConnectionAuth::Unauthenticated }
+            }
+        };
+        self.prewarm(proxy_host);
+        Ok(connection)
+    }
+
+    /// Hands back a connection that carried a plain HTTP request, once its response was read in full
+    /// and the proxy keeps it alive.
+    pub fn checkin(&self, proxy_host: &str, stream: TcpStream) {
+        self.add_idle(proxy_host, PooledConnection { stream, auth: ConnectionAuth::Authenticated });
+    }
+
+    pub fn preferred_scheme(&self, proxy_host: &str) -> Option<&'static str> {
+        self.state.lock().unwrap().preferred_schemes.get(proxy_host).map(|preferred| preferred.scheme)
+    }
+
+    pub fn remember_scheme(&self, proxy_host: &str, scheme: &'static str, auth: &[ProxyAuth], proxy_spn: &str) {
+        let preferred = PreferredScheme {
+            scheme,
+            auth: auth.to_vec(),
+            proxy_spn: proxy_spn.to_owned(),
+        };
+        self.state.lock().unwrap().preferred_schemes.insert(proxy_host.to_owned(), preferred);
+    }
+
+    pub fn forget_scheme(&self, proxy_host: &str) {
+        self.state.lock().unwrap().preferred_schemes.remove(proxy_host);
+    }
+
+    /// The proxies of a subnet in the order to try them: as `policy` says among the healthy ones,
+    /// then the others in case every proxy is down.
+    pub fn upstream_order(&self, proxy_hosts: &[String], policy: UpstreamPolicy) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut ordered = proxy_hosts.to_vec();
+
+        match policy {
+            UpstreamPolicy::Failover => {}
+            UpstreamPolicy::RoundRobin => {
+                let rotation = state.rotations.entry(proxy_hosts.join(",")).or_default();
+                ordered.rotate_left(*rotation % proxy_hosts.len().max(1));
+                *rotation = rotation.wrapping_add(1);
+            }
+            UpstreamPolicy::LeastConnections => {
+                ordered.sort_by_key(|proxy_host| {
+                    (state.open_tunnels.get(proxy_host).copied().unwrap_or(0), state.latency(proxy_host))
+                });
+            }
+        }
+
+        let now = Instant::now();
+        ordered.sort_by_key(|proxy_host| !state.is_healthy(proxy_host, now));
+        ordered
+    }
+
+    pub fn is_healthy(&self, proxy_host: &str) -> bool {
+        self.state.lock().unwrap().is_healthy(proxy_host, Instant::now())
+    }
+
+    /// Records an active health check, `latency` being `None` when it failed.
+    /// Returns whether the proxy went up or down since the previous one.
+    pub fn record_probe(&self, proxy_host: &str, latency: Option<Duration>) -> bool {
+        let previous = self.state.lock().unwrap().probes.insert(proxy_host.to_owned(), latency);
+        previous.is_none_or(|previous| previous.is_some() != latency.is_some())
+    }
+
+    /// Passive health marking: the proxy could not be reached, so others are tried first for a while.
+    pub fn mark_down(&self, proxy_host: &str) {
+        eprintln!("🔌 Upstream proxy {} marked down", proxy_host);
+        self.state.lock().unwrap().down_until.insert(proxy_host.to_owned(), Instant::now() + UPSTREAM_DOWN_FOR);
+    }
+
+    pub fn mark_up(&self, proxy_host: &str) {
+        self.state.lock().unwrap().down_until.remove(proxy_host);
+    }
+
+    /// Counts a tunnel through the proxy until the returned lease is dropped.
+    pub fn lease(&self, proxy_host: &str) -> UpstreamLease {
+        *self.state.lock().unwrap().open_tunnels.entry(proxy_host.to_owned()).or_default() += 1;
+        UpstreamLease {
+            pool: self.clone(),
+            proxy_host: proxy_host.to_owned(),
+        }
+    }
+
+    fn add_idle(&self, proxy_host: &str, connection: PooledConnection) {
+        let mut state = self.state.lock().unwrap();
+        let idle = state.idle.entry(proxy_host.to_owned()).or_default();
+        if idle.len() < self.config.max_idle {
+            idle.push(IdleConnection {
+                connection,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    fn take_idle(&self, proxy_host: &str) -> Option<PooledConnection> {
+        let mut state = self.state.lock().unwrap();
+        let idle = state.idle.get_mut(proxy_host)?;
+
+        while let Some(idle_connection) = idle.pop() {
+            if idle_connection.idle_since.elapsed() < self.config.idle_timeout && is_alive(&idle_connection.connection.stream) {
+                return Some(idle_connection.connection);
+            }
+        }
+        None
+    }
+
+    /// Computes the first `Proxy-Authorization` of the scheme the proxy last accepted, so a connection
+    /// opened ahead of time authenticates with its first request.
+    fn prepare_auth(&self, proxy_host: &str) -> ConnectionAuth {
+        let (known_challenge, auth, proxy_spn) = {
+            let state = self.state.lock().unwrap();
+            let Some(preferred) = state.preferred_schemes.get(proxy_host) else {
+                return ConnectionAuth::Unauthenticated;
+            };
+            let known_challenge = [AuthChallenge { scheme: preferred.scheme.to_owned(), params: String::new() }];
+            (known_challenge, preferred.auth.clone(), preferred.proxy_spn.clone())
+        };
+
+        // Digest is never a preferred scheme, so no request is needed for its digest.
+        let Some(mut authenticator) = select_authenticators(&auth, &known_challenge, &proxy_spn, "", "").into_iter().next() else {
+            return ConnectionAuth::Unauthenticated;
+        };
+        match authenticator.respond(None) {
+            Ok(authorization) => ConnectionAuth::Prepared(authenticator, authorization),
+            Err(e) => {
+                eprintln!("🔌 Could not prepare {} credentials for {}: {:#}", authenticator.scheme(), proxy_host, e);
+                ConnectionAuth::Unauthenticated
+            }
+        }
+    }
+
+    /// Opens connections in the background until `prewarm` idle ones are available for the proxy,
+    /// each with the credentials of the preferred scheme ready for its first request.
+    fn prewarm(&self, proxy_host: &str) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let idle_count = state.idle.get(proxy_host).map_or(0, |idle| idle.len());
+            if idle_count >= self.prewarm_target() || !state.warming.insert(proxy_host.to_owned()) {
+                return;
+            }
+        }
+
+        let pool = self.clone();
+        let proxy_host = proxy_host.to_owned();
+        tokio::spawn(async move {
+            loop {
+                let idle_count = pool.state.lock().unwrap().idle.get(&proxy_host).map_or(0, |idle| idle.len());
+                if idle_count >= pool.prewarm_target() {
+                    break;
+                }
+
+                match TcpStream::connect(&proxy_host).await {
+                    Ok(stream) => {
+                        let auth = pool.prepare_auth(&proxy_host);
+                        pool.add_idle(&proxy_host, PooledConnection { stream, auth });
+                    }
+                    Err(e) => {
+                        eprintln!("🔌 Could not pre-warm connection to {}: {}", &proxy_host, e);
+                        break;
+                    }
+                }
+            }
+            pool.state.lock().unwrap().warming.remove(&proxy_host);
+        });
+    }
+
+    fn prewarm_target(&self) -> usize {
+        self.config.prewarm.min(self.config.max_idle)
+    }
+}
+
+/// An idle proxy connection must have nothing to read: EOF means the proxy closed it,
+/// and unsolicited data means it is in an unknown state.
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut probe = [0; 1];
+    matches!(stream.try_read(&mut probe), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// A tunnel open through an upstream proxy, for the least-connections policy.
+pub(crate) struct UpstreamLease {
+    pool: UpstreamPool,
+    proxy_host: String,
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        if let Some(open_tunnels) = state.open_tunnels.get_mut(&self.proxy_host) {
+            *open_tunnels = open_tunnels.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{PoolConfig, UpstreamPolicy};
+    use crate::upstream_pool::UpstreamPool;
+
+    #[test]
+    fn test_upstream_order() {
+        let pool = UpstreamPool::new(PoolConfig::default());
+        let proxies = ["a:8080", "b:8080", "c:8080"].map(str::to_owned);
+
+        assert_eq!(pool.upstream_order(&proxies, UpstreamPolicy::RoundRobin), ["a:8080", "b:8080", "c:8080"]);
+        assert_eq!(pool.upstream_order(&proxies, UpstreamPolicy::RoundRobin), ["b:8080", "c:8080", "a:8080"]);
+
+        let _lease = pool.lease("a:8080");
+        assert_eq!(pool.upstream_order(&proxies, UpstreamPolicy::LeastConnections), ["b:8080", "c:8080", "a:8080"]);
+
+        pool.mark_down("b:8080");
+        assert_eq!(pool.upstream_order(&proxies, UpstreamPolicy::Failover), ["a:8080", "c:8080", "b:8080"]);
+        pool.mark_up("b:8080");
+        assert_eq!(pool.upstream_order(&proxies, UpstreamPolicy::Failover), ["a:8080", "b:8080", "c:8080"]);
+
+        assert!(pool.record_probe("a:8080", None));
+        assert!(!pool.record_probe("a:8080", None));
+        assert!(!pool.is_healthy("a:8080"));
+        assert_eq!(pool.upstream_order(&proxies, UpstreamPolicy::Failover), ["b:8080", "c:8080", "a:8080"]);
+    }
+}
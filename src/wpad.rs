@@ -0,0 +1,174 @@
+use crate::pac;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Time given to each WPAD candidate to answer with its PAC file.
+const WPAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Directories holding the DHCP leases of dhclient, NetworkManager and systemd-networkd.
+const LEASE_DIRS: [&str; 5] = ["/var/lib/dhcp", "/var/lib/dhclient", "/var/lib/NetworkManager", "/run/NetworkManager/devices", "/run/systemd/netif/leases"];
+
+/// Looks for the network's PAC file the way browsers do: the URL of DHCP option 252 first, then
+/// DNS WPAD with `http://wpad.<domain>/wpad.dat` for each search domain of the host, closest first,
+/// and their parents down to `devolve_to` if set.
+pub(crate) async fn discover_pac_url(devolve_to: Option<&str>) -> Option<String> {
+    if let Some(url) = dhcp_pac_url()
+        && is_pac_file(&url).await
+    {
+        return Some(url);
+    }
+
+    for domain in wpad_domains(&search_domains(), devolve_to) {
+        let host = format!("wpad.{}", domain);
+        if tokio::net::lookup_host((host.as_str(), 80)).await.is_err() {
+            continue;
+        }
+
+        let url = format!("http://{}/wpad.dat", host);
+        if is_pac_file(&url).await {
+            return Some(url);
+        }
+    }
+    None
+}
+
+async fn is_pac_file(url: &str) -> bool {
+    match tokio::time::timeout(WPAD_TIMEOUT, pac::fetch_script(url)).await {
+        Ok(Ok(script)) if script.contains("FindProxyForURL") => return true,
+        Ok(Ok(_)) => eprintln!("⚠️ {} is not a PAC file", url),
+        Ok(Err(e)) => eprintln!("⚠️ Could not fetch {}: {:#}", url, e),
+        Err(_) => eprintln!("⚠️ Fetching {} timed out", url),
+    }
+    false
+}
+
+/// The option 252 URL of the most recently written DHCP lease that has one.
+fn dhcp_pac_url() -> Option<String> {
+    let mut leases: Vec<(SystemTime, PathBuf)> = LEASE_DIRS
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .filter(|(_, path)| path.is_file())
+        .collect();
+    leases.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    leases
+        .iter()
+        .filter_map(|(_, path)| std::fs::read_to_string(path).ok())
+        .find_map(|lease| lease_wpad_url(&lease))
+}
+
+/// Option 252 of a lease file: the last `option wpad "<url>";` of a dhclient lease (also under the
+/// `wpad-url` or `unknown-252` names), or the hex `OPTION_252=` of a NetworkManager/systemd-networkd lease.
+fn lease_wpad_url(lease: &str) -> Option<String> {
+    let value = lease.lines().rev().find_map(|line| {
+        let line = line.trim();
+        if let Some(option) = line.strip_prefix("option ") {
+            let (name, value) = option.split_once(char::is_whitespace)?;
+            matches!(name, "wpad" | "wpad-url" | "unknown-252").then(|| value.trim().trim_end_matches(';').to_owned())
+        } else {
+            line.strip_prefix("OPTION_252=").map(|value| value.to_owned())
+        }
+    })?;
+
+    let url = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(quoted) => quoted.to_owned(),
+        None => {
+            // Unquoted values are hex, colon separated from dhclient.
+            let digits = value.replace(':', "");
+            let bytes = (0..digits.len())
+                .step_by(2)
+                .map(|i| digits.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                .collect::<Option<Vec<u8>>>()?;
+            String::from_utf8(bytes).ok()?
+        }
+    };
+    // Windows DHCP servers count the terminating NUL in the option.
+    let url = url.trim_end_matches('\0').trim();
+    (url.starts_with("http://") || url.starts_with("https://")).then(|| url.to_owned())
+}
+
+/// DNS search domains of the host: `/etc/resolv.conf`, the Windows domain, then the host name's domain.
+fn search_domains() -> Vec<String> {
+    let mut domains = std::fs::read_to_string("/etc/resolv.conf")
+        .map(|resolv_conf| resolv_conf_domains(&resolv_conf))
+        .unwrap_or_default();
+    let hostname_domain = dns_lookup::get_hostname()
+        .ok()
+        .and_then(|hostname| hostname.split_once('.').map(|(_, domain)| domain.to_owned()));
+
+    for domain in std::env::var("USERDNSDOMAIN").ok().into_iter().chain(hostname_domain) {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if !domain.is_empty() && !domains.contains(&domain) {
+            domains.push(domain);
+        }
+    }
+    domains
+}
+
+/// Domains of the `search` and `domain` lines of a `resolv.conf`.
+fn resolv_conf_domains(resolv_conf: &str) -> Vec<String> {
+    let mut domains: Vec<String> = Vec::new();
+    for line in resolv_conf.lines() {
+        let mut words = line.split_whitespace();
+        if !matches!(words.next(), Some("search" | "domain")) {
+            continue;
+        }
+        for domain in words {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            if !domain.is_empty() && !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    domains
+}
+
+/// Each search domain, followed by its parents down to `devolve_to` when it is under that domain
+/// (`a.b.corp.com`, `b.corp.com`, `corp.com`). Devolving is opt-in: past the organisation's own
+/// domain, `wpad.<parent>` may belong to anyone (`wpad.co.uk`).
+fn wpad_domains(search_domains: &[String], devolve_to: Option<&str>) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    for domain in search_domains {
+        let mut candidate = domain.as_str();
+        loop {
+            if !candidates.iter().any(|known| known == candidate) {
+                candidates.push(candidate.to_owned());
+            }
+            match (devolve_to, candidate.split_once('.')) {
+                (Some(devolve_to), Some((_, parent))) if candidate != devolve_to && candidate.ends_with(&format!(".{}", devolve_to)) => {
+                    candidate = parent;
+                }
+                _ => break,
+            }
+        }
+    }
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wpad::{lease_wpad_url, resolv_conf_domains, wpad_domains};
+
+    #[test]
+    fn test_wpad_candidates() {
+        let domains = resolv_conf_domains("# comment\nnameserver 10.0.0.1\ndomain Paris.Corp.Example.com.\nsearch corp.example.com lab\n");
+        assert_eq!(domains, vec!["paris.corp.example.com", "corp.example.com", "lab"]);
+        assert_eq!(wpad_domains(&domains, None), vec!["paris.corp.example.com", "corp.example.com", "lab"]);
+        assert_eq!(
+            wpad_domains(&["a.paris.corp.example.com".to_owned(), "lab".to_owned()], Some("corp.example.com")),
+            vec!["a.paris.corp.example.com", "paris.corp.example.com", "corp.example.com", "lab"]
+        );
+    }
+
+    #[test]
+    fn test_lease_wpad_url() {
+        let dhclient = "lease {\n  option wpad \"http://old.example.com/wpad.dat\";\n}\nlease {\n  interface \"eth0\";\n  option wpad \"http://pac.corp.example.com/proxy.pac\";\n}\n";
+        assert_eq!(lease_wpad_url(dhclient).as_deref(), Some("http://pac.corp.example.com/proxy.pac"));
+        assert_eq!(lease_wpad_url("option unknown-252 68:74:74:70:3a:2f:2f:70:2f:00;").as_deref(), Some("http://p/"));
+        assert_eq!(lease_wpad_url("ADDRESS=10.0.0.5\nOPTION_252=687474703a2f2f702f7761642e646174\n").as_deref(), Some("http://p/wad.dat"));
+        assert_eq!(lease_wpad_url("option domain-name \"corp.example.com\";"), None);
+    }
+}